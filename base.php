@@ -33,6 +33,28 @@ abstract class TransactionBase
     {
         return $this->db->rollBack();
     }
+
+    // One placeholder for each value of an array parameter. An empty array becomes NULL, so `IN (NULL)` matches nothing
+    protected static function placeholders(array $values): string
+    {
+        return $values ? implode(", ", array_fill(0, count($values), "?")) : "NULL";
+    }
+
+    // Like placeholders(), with named placeholders: `:ids__0, :ids__1`
+    protected static function namedPlaceholders(string $name, array $values): string
+    {
+        return $values ? implode(", ", array_map(fn($i) => ":{$name}__$i", array_keys(array_values($values)))) : "NULL";
+    }
+
+    // The values of an array parameter, with the names from namedPlaceholders()
+    protected static function namedValues(string $name, array $values): array
+    {
+        $named = [];
+        foreach (array_values($values) as $i => $value) {
+            $named["{$name}__$i"] = $value;
+        }
+        return $named;
+    }
 }
 
 abstract class AsyncTransaction
@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+const SCHEMA: &str = "CREATE TABLE Books (Id INT PRIMARY KEY, BookName TEXT NOT NULL, Blurb TEXT);";
+
+// Runs lex_sql on arbitrary SQL as the body of a single query. `;` and `-` are taken out so the file lexers
+// don't split it into other queries and comments.
+fuzz_target!(|text: &str| {
+    let source = format!("--#fuzz\n{};", text.replace([';', '-'], " "));
+    let mut schema = safe_sql::Schema::default();
+    schema.parse_file("schema.sql", SCHEMA, safe_sql::Dialect::Any, &mut Vec::new());
+    for schema in [None, Some(&schema)] {
+        let file = safe_sql::parse_file("fuzz.sql", "fuzz", &source, safe_sql::Dialect::Any, schema);
+        for diagnostic in &file.diagnostics {
+            let _ = diagnostic.to_string();
+        }
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+const SCHEMA: &str = "CREATE TABLE Books (Id INT PRIMARY KEY, BookName TEXT NOT NULL, Blurb TEXT);";
+
+// Runs lex, lex_2 and lex_sql over arbitrary file contents, resolves its includes and renders every diagnostic,
+// without and with a schema to infer types from.
+fuzz_target!(|text: &str| {
+    let mut schema = safe_sql::Schema::default();
+    schema.parse_file("schema.sql", SCHEMA, safe_sql::Dialect::Any, &mut Vec::new());
+    for schema in [None, Some(&schema)] {
+        let mut files = vec![safe_sql::parse_file("fuzz.sql", "fuzz", text, safe_sql::Dialect::Any, schema)];
+        safe_sql::resolve_includes(&mut files, safe_sql::Dialect::Any, schema);
+        for diagnostic in &files[0].diagnostics {
+            let _ = diagnostic.to_string();
+        }
+    }
+});
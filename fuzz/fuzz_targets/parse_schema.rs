@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Reads arbitrary schema files and renders every diagnostic.
+fuzz_target!(|text: &str| {
+    let mut schema = safe_sql::Schema::default();
+    let mut errors = Vec::new();
+    schema.parse_file("schema.sql", text, safe_sql::Dialect::Any, &mut errors);
+    for diagnostic in &errors {
+        let _ = diagnostic.to_string();
+    }
+});
@@ -0,0 +1,619 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    diagnostic::Diagnostic,
+    schema::{words, Affinity, Column, Schema, Table, Word},
+    sqlfile::{flatten, Query, QueryText, SqlToken},
+    Dialect, ParsedFile
+};
+
+// A word of a query, or one of its annotations
+enum Piece<'a> {
+    Word(Word),
+    Variable(&'a str),
+    Return(&'a str) // column or expression
+}
+
+impl Piece<'_> {
+    fn word(&self) -> Option<&Word> {
+        match self {
+            Piece::Word(word) => Some(word),
+            _ => None
+        }
+    }
+
+    fn is(&self, keyword: &str) -> bool {
+        self.word().is_some_and(|word| word.is(keyword))
+    }
+
+    fn is_any(&self, keywords: &[&str]) -> bool {
+        self.word().is_some_and(|word| word.is_any(keywords))
+    }
+
+    fn is_symbol(&self, symbol: char) -> bool {
+        self.word() == Some(&Word::Symbol(symbol))
+    }
+
+    fn identifier(&self) -> Option<&str> {
+        self.word().and_then(Word::identifier)
+    }
+}
+
+fn pieces<'a>(tokens: &'a [SqlToken], dialect: Dialect) -> Vec<Piece<'a>> {
+    let mut pieces = Vec::new();
+    for token in flatten(tokens) {
+        match token {
+            SqlToken::Sql(sql) => pieces.extend(words(sql, dialect).into_iter().map(|(_, word)| Piece::Word(word))),
+            SqlToken::Variable(name, _) => pieces.push(Piece::Variable(name)),
+            SqlToken::Return(_, expression, _) => pieces.push(Piece::Return(expression)),
+            SqlToken::Optional(_) | SqlToken::Include(_, _) => {}
+        }
+    }
+    pieces
+}
+
+// Words that can follow a table name, so they aren't its alias
+const NOT_ALIASES: [&str; 24] = [
+    "WHERE", "ON", "USING", "JOIN", "LEFT", "RIGHT", "INNER", "OUTER", "FULL", "CROSS", "NATURAL", "GROUP", "ORDER", "LIMIT",
+    "HAVING", "SET", "VALUES", "VALUE", "SELECT", "UNION", "OFFSET", "FOR", "RETURNING", "DEFAULT"
+];
+
+// The tables a query reads or writes, by their name and alias in lower case.
+fn tables<'a>(schema: &'a Schema, pieces: &[Piece]) -> BTreeMap<String, &'a Table> {
+    let mut tables = BTreeMap::new();
+    let mut i = 0;
+    while i < pieces.len() {
+        let in_from = pieces[i].is("FROM");
+        if !pieces[i].is_any(&["FROM", "JOIN", "UPDATE", "INTO"]) {
+            i += 1;
+            continue;
+        }
+        i += 1;
+        // `FROM a, b` reads both tables
+        loop {
+            let mut name = None;
+            while let Some(part) = pieces.get(i).and_then(Piece::identifier) {
+                name = Some(part);
+                i += 1;
+                if !pieces.get(i).is_some_and(|piece| piece.is_symbol('.')) {
+                    break;
+                }
+                i += 1;
+            }
+            let Some(table) = name.and_then(|name| schema.table(name)) else {
+                break;
+            };
+            tables.insert(table.name.to_lowercase(), table);
+            if pieces.get(i).is_some_and(|piece| piece.is("AS")) {
+                i += 1;
+            }
+            if let Some(alias) = pieces.get(i).filter(|piece| !piece.is_any(&NOT_ALIASES)).and_then(Piece::identifier) {
+                tables.insert(alias.to_lowercase(), table);
+                i += 1;
+            }
+            if !(in_from && pieces.get(i).is_some_and(|piece| piece.is_symbol(','))) {
+                break;
+            }
+            i += 1;
+        }
+    }
+    tables
+}
+
+// The column `table.column` or `column` refers to. An unqualified column has to be in exactly one of the tables.
+fn find_column<'a>(tables: &BTreeMap<String, &'a Table>, qualifier: Option<&str>, name: &str) -> Option<(&'a Table, &'a Column)> {
+    match qualifier {
+        Some(qualifier) => {
+            let table = tables.get(&qualifier.to_lowercase())?;
+            Some((*table, table.column(name)?))
+        }
+        None => {
+            let mut found = tables.values().filter_map(|table| Some((*table, table.column(name)?)));
+            let first = found.next()?;
+            // the same table under its name and alias isn't ambiguous
+            if found.any(|(table, _)| table.name != first.0.name) { None } else { Some(first) }
+        }
+    }
+}
+
+// The column a return value reads, if it is a column and not an expression. `AS alias` is allowed.
+fn return_column<'a>(tables: &BTreeMap<String, &'a Table>, expression: &str, dialect: Dialect) -> Option<(&'a Table, &'a Column)> {
+    let mut words: Vec<Word> = words(expression, dialect).into_iter().map(|(_, word)| word).collect();
+    if words.len() >= 2 && words[words.len() - 2].is("AS") {
+        words.truncate(words.len() - 2);
+    }
+    match words.as_slice() {
+        [column] => find_column(tables, None, column.identifier()?),
+        [.., table, Word::Symbol('.'), column] => find_column(tables, table.identifier(), column.identifier()?),
+        _ => None
+    }
+}
+
+// Reads `table.column` or `column` ending just before `end`.
+fn column_before<'a>(pieces: &'a [Piece], end: usize) -> Option<(Option<&'a str>, &'a str)> {
+    let column = pieces.get(end.checked_sub(1)?)?.identifier()?;
+    if end >= 3 && pieces[end - 2].is_symbol('.') {
+        return Some((Some(pieces[end - 3].identifier()?), column));
+    }
+    Some((None, column))
+}
+
+// Reads `table.column` or `column` starting at `start`.
+fn column_after<'a>(pieces: &'a [Piece], start: usize) -> Option<(Option<&'a str>, &'a str)> {
+    let first = pieces.get(start)?.identifier()?;
+    if pieces.get(start + 1).is_some_and(|piece| piece.is_symbol('.')) {
+        return Some((Some(first), pieces.get(start + 2)?.identifier()?));
+    }
+    Some((None, first))
+}
+
+fn is_comparison(piece: &Piece) -> bool {
+    ['=', '<', '>', '!'].iter().any(|symbol| piece.is_symbol(*symbol))
+}
+
+// The column the variable at `i` is compared to, as in `column = $x`, `column LIKE $x`, `column IN ($x)` or `$x < column`.
+fn compared_column<'a>(pieces: &'a [Piece], i: usize) -> Option<(Option<&'a str>, &'a str)> {
+    let mut start = i;
+    if start >= 2 && pieces[start - 1].is_symbol('(') && pieces[start - 2].is("IN") {
+        start -= 2;
+    } else if start >= 1 && pieces[start - 1].is_any(&["LIKE", "ILIKE", "BETWEEN"]) {
+        start -= 1;
+    } else {
+        while start > i.saturating_sub(2) && start >= 1 && is_comparison(&pieces[start - 1]) {
+            start -= 1;
+        }
+    }
+    if start < i {
+        if start >= 1 && pieces[start - 1].is("NOT") {
+            start -= 1;
+        }
+        if let Some(column) = column_before(pieces, start) {
+            return Some(column);
+        }
+    }
+    let mut end = i + 1;
+    while end < i + 3 && pieces.get(end).is_some_and(is_comparison) {
+        end += 1;
+    }
+    if end > i + 1 { column_after(pieces, end) } else { None }
+}
+
+// Whether the variable at `i` is assigned with `SET column = $x`, or MySQL's `ON DUPLICATE KEY UPDATE column = $x`.
+fn is_assignment(pieces: &[Piece], i: usize) -> bool {
+    let clause = pieces[..i].iter().rev().find(|piece| piece.is_any(&["SET", "UPDATE", "WHERE", "ON", "HAVING", "SELECT", "VALUES"]));
+    i >= 1 && pieces[i - 1].is_symbol('=') && !(i >= 2 && is_comparison(&pieces[i - 2])) && clause.is_some_and(|piece| piece.is_any(&["SET", "UPDATE"]))
+}
+
+// The columns filled by the variables of `INSERT INTO table (a, b) VALUES ($a, $b)`, by variable name.
+fn inserted_columns<'a>(pieces: &[Piece], tables: &BTreeMap<String, &'a Table>) -> BTreeMap<String, (&'a Table, &'a Column)> {
+    let mut inserted = BTreeMap::new();
+    let Some(into) = pieces.windows(2).position(|pair| pair[0].is("INSERT") && pair[1].is("INTO")) else {
+        return inserted;
+    };
+    let mut i = into + 2;
+    let mut table = None;
+    while let Some(part) = pieces.get(i).and_then(Piece::identifier) {
+        table = tables.get(&part.to_lowercase());
+        i += 1;
+        if !pieces.get(i).is_some_and(|piece| piece.is_symbol('.')) {
+            break;
+        }
+        i += 1;
+    }
+    let Some(table) = table else {
+        return inserted;
+    };
+    if !pieces.get(i).is_some_and(|piece| piece.is_symbol('(')) {
+        return inserted;
+    }
+    let mut columns = Vec::new();
+    i += 1;
+    while let Some(piece) = pieces.get(i) {
+        i += 1;
+        if piece.is_symbol(')') {
+            break;
+        }
+        if let Some(name) = piece.identifier() {
+            columns.push(table.column(name));
+        }
+    }
+    if !pieces.get(i).is_some_and(|piece| piece.is_any(&["VALUES", "VALUE"])) {
+        return inserted;
+    }
+    i += 1;
+    // every row of `VALUES (...), (...)`
+    while pieces.get(i).is_some_and(|piece| piece.is_symbol('(')) {
+        i += 1;
+        let mut column = 0;
+        let mut depth = 0;
+        let mut item_start = i;
+        while let Some(piece) = pieces.get(i) {
+            let end_of_item = depth == 0 && (piece.is_symbol(',') || piece.is_symbol(')'));
+            if end_of_item {
+                // only a variable on its own fills the column; `$x + 1` doesn't
+                if let (Piece::Variable(name), 1, Some(Some(found))) = (&pieces[item_start], i - item_start, columns.get(column)) {
+                    inserted.entry(name.to_string()).or_insert((*table, *found));
+                }
+                column += 1;
+                item_start = i + 1;
+            }
+            i += 1;
+            if end_of_item && piece.is_symbol(')') {
+                break;
+            }
+            if piece.is_symbol('(') {
+                depth += 1;
+            } else if piece.is_symbol(')') {
+                depth -= 1;
+            }
+        }
+        if !pieces.get(i).is_some_and(|piece| piece.is_symbol(',')) {
+            break;
+        }
+        i += 1;
+    }
+    inserted
+}
+
+/// What an annotation of a query refers to in the schema.
+pub enum Reference<'a> {
+    /// `@column: type` reads the column.
+    Read(&'a Table, &'a Column),
+    /// `$variable: type` is compared to the column.
+    Compared(&'a Table, &'a Column),
+    /// `$variable: type` is inserted into the column, or assigned to it by `SET column = $variable`.
+    Stored(&'a Table, &'a Column)
+}
+
+/// The columns the annotations of `tokens` refer to: return values by their column or expression, variables by
+/// their name. Annotations whose column isn't known are left out.
+pub fn references<'a>(schema: &'a Schema, tokens: &[SqlToken], dialect: Dialect) -> (BTreeMap<String, Reference<'a>>, BTreeMap<String, Reference<'a>>) {
+    let pieces = pieces(tokens, dialect);
+    let tables = tables(schema, &pieces);
+    let mut returns = BTreeMap::new();
+    let mut variables = BTreeMap::new();
+    for (name, (table, column)) in inserted_columns(&pieces, &tables) {
+        variables.insert(name, Reference::Stored(table, column));
+    }
+    for (i, piece) in pieces.iter().enumerate() {
+        match piece {
+            Piece::Return(expression) => {
+                if let Some((table, column)) = return_column(&tables, expression, dialect) {
+                    returns.insert(expression.to_string(), Reference::Read(table, column));
+                }
+            }
+            Piece::Variable(name) if !variables.contains_key(*name) => {
+                let found = compared_column(&pieces, i).and_then(|(qualifier, column)| find_column(&tables, qualifier, column));
+                if let Some((table, column)) = found {
+                    let reference = if is_assignment(&pieces, i) { Reference::Stored(table, column) } else { Reference::Compared(table, column) };
+                    variables.insert(name.to_string(), reference);
+                }
+            }
+            _ => {}
+        }
+    }
+    (returns, variables)
+}
+
+// Splits a PHP type like `?int` into whether it is nullable and the type. Arrays are checked by their elements.
+pub fn split_type(type_name: &str) -> (bool, String) {
+    let base = type_name.strip_suffix("[]").unwrap_or(type_name);
+    match base.strip_prefix('?') {
+        Some(base) => (true, base.to_lowercase()),
+        None => (false, base.to_lowercase())
+    }
+}
+
+// Byte offset of the first `needle` in the query's SQL, or its header if it can't be found, like when it is
+// in an included fragment.
+fn locate(query: &QueryText, needle: &str) -> usize {
+    match query.sql.text.find(needle) {
+        Some(byte) => query.sql.offset(query.sql.text[..byte].chars().count()),
+        None => query.header
+    }
+}
+
+fn column_note(schema: &Schema, table: &Table, column: &Column) -> Diagnostic {
+    let (path, source) = &schema.files[table.file];
+    Diagnostic::note(path, source, column.offset, format!("`{}.{}` is declared here", table.name, column.name))
+}
+
+// Checks the types of the annotations of one query against the columns they refer to.
+fn check_query(schema: &Schema, file: &ParsedFile, query: &Query, dialect: Dialect) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let (returns, variables) = references(schema, &query.tokens, dialect);
+    let mut checked = Vec::new();
+    for token in flatten(&query.tokens) {
+        let (annotation, reference, type_name) = match token {
+            SqlToken::Return(_, expression, type_name) => (format!("@{}", expression), returns.get(expression), type_name),
+            SqlToken::Variable(name, type_name) => (format!("${}", name), variables.get(name), type_name),
+            _ => continue
+        };
+        let Some(reference) = reference else {
+            continue;
+        };
+        if checked.contains(&annotation) {
+            continue;
+        }
+        let offset = locate(&query.text, &annotation);
+        checked.push(annotation.clone());
+        let (nullable, base) = split_type(type_name);
+        let (table, column, verb) = match reference {
+            Reference::Read(table, column) => (*table, *column, "reads"),
+            Reference::Compared(table, column) => (*table, *column, "is compared to"),
+            Reference::Stored(table, column) => (*table, *column, "is stored in")
+        };
+        let affinity = Affinity::of(&column.sql_type);
+        let allowed = match reference {
+            Reference::Read(_, _) => affinity.php_types(),
+            _ => affinity.param_types()
+        };
+        let known = ["int", "float", "string", "bool"].contains(&base.as_str());
+        let message = |problem: String| format!("`{}: {}` {} `{}.{}`, {}", annotation, type_name, verb, table.name, column.name, problem);
+        if known && !allowed.is_empty() && !allowed.contains(&base.as_str()) {
+            let problem = format!("which is `{}`, so it should be `{}{}`", column.sql_type, if nullable { "?" } else { "" }, allowed[0]);
+            // a wrong return type breaks the row class, a wrong parameter type is usually converted by the database
+            let diagnostic = match reference {
+                Reference::Read(_, _) => Diagnostic::new(&file.path, &file.source, offset, message(problem)),
+                _ => Diagnostic::warning(&file.path, &file.source, offset, message(problem))
+            };
+            diagnostics.push(diagnostic.with_note(column_note(schema, table, column)));
+        } else if matches!(reference, Reference::Read(_, _)) && known && column.nullable && !nullable {
+            let problem = format!("which can be NULL, so it should be `?{}`", type_name);
+            diagnostics.push(Diagnostic::warning(&file.path, &file.source, offset, message(problem)).with_note(column_note(schema, table, column)));
+        } else if matches!(reference, Reference::Stored(_, _)) && !column.nullable && nullable {
+            let problem = "which is NOT NULL".to_string();
+            diagnostics.push(Diagnostic::warning(&file.path, &file.source, offset, message(problem)).with_note(column_note(schema, table, column)));
+        }
+    }
+    diagnostics
+}
+
+/// Checks the declared types of every query against the schema, adding the problems to the files' diagnostics.
+pub fn check(schema: &Schema, files: &mut [ParsedFile], dialect: Dialect) {
+    for file in files.iter_mut() {
+        let diagnostics: Vec<Diagnostic> = file.queries.iter().flat_map(|query| check_query(schema, file, query, dialect)).collect();
+        file.diagnostics.extend(diagnostics);
+        file.diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    }
+}
+
+// The PHP type of a value of `column`, if anything is known about its type.
+fn column_type(column: &Column, nullable: bool) -> Option<String> {
+    let base = Affinity::of(&column.sql_type).php_types().first()?;
+    Some(if nullable { format!("?{}", base) } else { base.to_string() })
+}
+
+struct Inference<'a> {
+    returns: BTreeMap<String, Reference<'a>>,
+    variables: BTreeMap<String, Reference<'a>>,
+    // types written out on another use of the same variable
+    explicit: BTreeMap<String, String>
+}
+
+impl Inference<'_> {
+    // Fills in the types of `tokens`. Fails with the annotation that has no type and why.
+    fn fill(&self, tokens: Vec<SqlToken>, optional: bool) -> Result<Vec<SqlToken>, (String, String)> {
+        let mut out = Vec::new();
+        for token in tokens {
+            out.push(match token {
+                SqlToken::Return(property, expression, type_name) if type_name.is_empty() => {
+                    match self.returns.get(&expression) {
+                        Some(Reference::Read(_, column)) => match column_type(column, column.nullable) {
+                            Some(type_name) => SqlToken::Return(property, expression, type_name),
+                            None => return Err((format!("@{}", expression), format!("can't infer a PHP type for `@{}` from `{}`, so write it like `@{}: string`", expression, column.sql_type, expression)))
+                        },
+                        // `lex_sql` only leaves out the type of schema columns, so this isn't a MySQL user variable
+                        _ => {
+                            let fix = if expression.contains('.') {
+                                format!("write it like `@{}: string`", expression)
+                            } else {
+                                format!("qualify it like `@table.{}` or write it like `@{}: string`. A MySQL user variable with the name of a column needs quotes, like @`{}`", expression, expression, expression)
+                            };
+                            return Err((format!("@{}", expression), format!("can't infer the type of `@{}` because it isn't a column of exactly one of the query's tables, so {}", expression, fix)));
+                        }
+                    }
+                }
+                SqlToken::Variable(name, type_name) if type_name.is_empty() => {
+                    let annotation = format!("${}", name);
+                    if let Some(type_name) = self.explicit.get(&name) {
+                        SqlToken::Variable(name, type_name.clone())
+                    } else {
+                        let (column, nullable) = match self.variables.get(&name) {
+                            // comparing to NULL is never true, so only optional fragments need null
+                            Some(Reference::Compared(_, column)) => (*column, optional),
+                            Some(Reference::Stored(_, column)) => (*column, optional || column.nullable),
+                            _ => return Err((annotation.clone(), format!("can't infer the type of `{}` because it isn't compared to or stored in a column of the schema, so write it like `{}: string`", annotation, annotation)))
+                        };
+                        match column_type(column, nullable) {
+                            Some(type_name) => SqlToken::Variable(name, type_name),
+                            None => return Err((annotation.clone(), format!("can't infer a PHP type for `{}` from `{}`, so write it like `{}: string`", annotation, column.sql_type, annotation)))
+                        }
+                    }
+                }
+                SqlToken::Optional(inner) => SqlToken::Optional(self.fill(inner, true)?),
+                token => token
+            });
+        }
+        Ok(out)
+    }
+}
+
+/// Fills in the types of bare `$variable`s and `@column`s from the columns they refer to. A variable written with
+/// a type elsewhere in the query gets that type. Fails if a column can't be found, or is in more than one table.
+pub fn infer_types(schema: &Schema, source: &str, query: &QueryText, tokens: Vec<SqlToken>, dialect: Dialect) -> Result<Vec<SqlToken>, Diagnostic> {
+    let untyped = flatten(&tokens).into_iter().any(|token| matches!(token, SqlToken::Return(_, _, type_name) | SqlToken::Variable(_, type_name) if type_name.is_empty()));
+    if !untyped {
+        return Ok(tokens);
+    }
+    let (returns, variables) = references(schema, &tokens, dialect);
+    let mut explicit = BTreeMap::new();
+    for token in flatten(&tokens) {
+        if let SqlToken::Variable(name, type_name) = token {
+            if !type_name.is_empty() {
+                explicit.entry(name.clone()).or_insert(type_name.clone());
+            }
+        }
+    }
+    let inference = Inference { returns, variables, explicit };
+    inference.fill(tokens, false).map_err(|(annotation, message)| Diagnostic::new(&query.path, source, locate(query, &annotation), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diagnostic::Level, parse_file, resolve_includes};
+
+    const SCHEMA: &str = "CREATE TABLE Books (Id INT PRIMARY KEY, AuthorId INT NOT NULL, BookName TEXT NOT NULL, Blurb TEXT, Price DECIMAL(10, 2));
+CREATE TABLE Authors (Id INT PRIMARY KEY, Name TEXT NOT NULL);";
+
+    fn schema() -> Schema {
+        let mut schema = Schema::default();
+        let mut errors = Vec::new();
+        schema.parse_file("schema.sql", SCHEMA, Dialect::Any, &mut errors);
+        assert_eq!(errors, Vec::new());
+        schema
+    }
+
+    fn parse(source: &str) -> ParsedFile {
+        let schema = schema();
+        let mut files = vec![parse_file("test.sql", "test", source, Dialect::Any, Some(&schema))];
+        resolve_includes(&mut files, Dialect::Any, Some(&schema));
+        check(&schema, &mut files, Dialect::Any);
+        files.pop().unwrap()
+    }
+
+    // Level, line, column and message of every problem in `source`.
+    fn problems(source: &str) -> Vec<(Level, usize, usize, String)> {
+        parse(source).diagnostics.into_iter().map(|diagnostic| (diagnostic.level, diagnostic.line, diagnostic.column, diagnostic.message)).collect()
+    }
+
+    #[test]
+    fn matching_types() {
+        let source = "--#a\nSELECT @Id: int, @Blurb: ?string, @Price: ?string, @COUNT(*) AS n: string FROM Books WHERE Id = $id: int;\n\
+            --#b\nINSERT INTO Books (Id, AuthorId, BookName, Blurb) VALUES ($id: int, $author: int, $name: string, $blurb: ?string);";
+        assert_eq!(problems(source), Vec::new());
+    }
+
+    #[test]
+    fn wrong_return_type_is_an_error() {
+        let file = parse("--#a\nSELECT @b.Id: string FROM Books b;");
+        assert_eq!(file.diagnostics.len(), 1);
+        let error = &file.diagnostics[0];
+        assert_eq!((error.level, error.line, error.column), (Level::Error, 2, 8));
+        assert_eq!(error.message, "`@b.Id: string` reads `Books.Id`, which is `INT`, so it should be `int`");
+        assert_eq!((error.notes[0].path.as_str(), error.notes[0].line, error.notes[0].column), ("schema.sql", 1, 21));
+    }
+
+    #[test]
+    fn wrong_variable_type_is_a_warning() {
+        assert_eq!(problems("--#a\nSELECT @Id: int FROM Books WHERE BookName = $name: int;"), vec![
+            (Level::Warning, 2, 45, "`$name: int` is compared to `Books.BookName`, which is `TEXT`, so it should be `string`".to_string())
+        ]);
+    }
+
+    #[test]
+    fn nullability() {
+        assert_eq!(problems("--#a\nSELECT @Blurb: string FROM Books;\n--#b\nUPDATE Books SET BookName = $name: ?string WHERE Id = $id: int;"), vec![
+            (Level::Warning, 2, 8, "`@Blurb: string` reads `Books.Blurb`, which can be NULL, so it should be `?string`".to_string()),
+            (Level::Warning, 4, 29, "`$name: ?string` is stored in `Books.BookName`, which is NOT NULL".to_string())
+        ]);
+    }
+
+    #[test]
+    fn joined_tables_and_aliases() {
+        assert_eq!(problems("--#a\nSELECT @a.Name: int FROM Books AS b JOIN Authors a ON a.Id = b.AuthorId;"), vec![
+            (Level::Error, 2, 8, "`@a.Name: int` reads `Authors.Name`, which is `TEXT`, so it should be `string`".to_string())
+        ]);
+        // `Id` is in both tables, so it isn't checked
+        assert_eq!(problems("--#a\nSELECT @Id: string FROM Books b JOIN Authors a ON a.Id = b.AuthorId;"), Vec::new());
+    }
+
+    // Every annotation of the only query in `source` and its type, which has to parse without problems.
+    fn types(source: &str) -> Vec<(String, String)> {
+        let file = parse(source);
+        assert_eq!(file.diagnostics, Vec::new());
+        flatten(&file.queries[0].tokens).into_iter().filter_map(|token| match token {
+            SqlToken::Return(_, expression, type_name) => Some((format!("@{}", expression), type_name.clone())),
+            SqlToken::Variable(name, type_name) => Some((format!("${}", name), type_name.clone())),
+            _ => None
+        }).collect()
+    }
+
+    fn typed(annotations: &[(&str, &str)]) -> Vec<(String, String)> {
+        annotations.iter().map(|(annotation, type_name)| (annotation.to_string(), type_name.to_string())).collect()
+    }
+
+    #[test]
+    fn infers_returns_from_their_columns() {
+        assert_eq!(
+            types("--#a\nSELECT @b.Id, @Blurb, @Price, @a.Name FROM Books b JOIN Authors a ON a.Id = b.AuthorId;"),
+            typed(&[("@b.Id", "int"), ("@Blurb", "?string"), ("@Price", "?string"), ("@a.Name", "string")])
+        );
+    }
+
+    #[test]
+    fn infers_comparisons() {
+        assert_eq!(
+            types("--#a\nSELECT @BookName FROM Books b WHERE b.Id = $id AND $min <= Price AND BookName LIKE $name AND AuthorId IN ($authors);"),
+            typed(&[("@BookName", "string"), ("$id", "int"), ("$min", "string"), ("$name", "string"), ("$authors", "int")])
+        );
+    }
+
+    #[test]
+    fn infers_inserts() {
+        assert_eq!(
+            types("--#a\nINSERT INTO Books (Id, AuthorId, BookName, Blurb) VALUES ($id, $author, $name, $blurb), ($id2, $author, $name2, NULL);"),
+            typed(&[("$id", "int"), ("$author", "int"), ("$name", "string"), ("$blurb", "?string"), ("$id2", "int"), ("$author", "int"), ("$name2", "string")])
+        );
+    }
+
+    #[test]
+    fn infers_update_assignments() {
+        assert_eq!(
+            types("--#a\nUPDATE Books SET Blurb = $blurb, BookName = $name WHERE Id = $id;"),
+            typed(&[("$blurb", "?string"), ("$name", "string"), ("$id", "int")])
+        );
+    }
+
+    #[test]
+    fn optional_filters_are_nullable() {
+        assert_eq!(
+            types("--#a\nSELECT @Id FROM Books WHERE 1 = 1 [[ AND AuthorId = $author ]] [[ AND BookName LIKE $name ]];"),
+            typed(&[("@Id", "int"), ("$author", "?int"), ("$name", "?string")])
+        );
+    }
+
+    #[test]
+    fn explicit_types_win() {
+        assert_eq!(
+            types("--#a\nSELECT @Id FROM Books WHERE Price > $price OR Price < $price: float;"),
+            typed(&[("@Id", "int"), ("$price", "float"), ("$price", "float")])
+        );
+    }
+
+    #[test]
+    fn user_variables_stay_in_the_sql() {
+        let file = parse("--#a\nSELECT @Id, @Id + 1 AS next, @`Name` FROM Books;");
+        assert_eq!(file.diagnostics, Vec::new());
+        assert_eq!(file.queries[0].tokens, vec![
+            SqlToken::Sql("SELECT ".to_string()),
+            SqlToken::Return("Id".to_string(), "Id".to_string(), "int".to_string()),
+            SqlToken::Sql(", @Id + 1 AS next, @`Name` FROM Books".to_string())
+        ]);
+    }
+
+    #[test]
+    fn columns_that_cant_be_found() {
+        let ambiguous = problems("--#a\nSELECT @Id FROM Books b JOIN Authors a ON a.Id = b.AuthorId;");
+        assert_eq!((ambiguous[0].1, ambiguous[0].2), (2, 8));
+        assert!(ambiguous[0].3.starts_with("can't infer the type of `@Id` because it isn't a column of exactly one of the query's tables"), "{}", ambiguous[0].3);
+        // the columns of a common table expression aren't in the schema
+        let subquery = problems("--#a\nWITH x AS (SELECT BookName AS Name FROM Books) SELECT @Name FROM x;");
+        assert!(subquery[0].3.starts_with("can't infer the type of `@Name`"), "{}", subquery[0].3);
+        let variable = problems("--#a\nSELECT @Id FROM Books WHERE LENGTH(BookName) > $length;");
+        assert_eq!(variable, vec![
+            (Level::Error, 2, 48, "can't infer the type of `$length` because it isn't compared to or stored in a column of the schema, so write it like `$length: string`".to_string())
+        ]);
+    }
+}
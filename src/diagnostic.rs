@@ -0,0 +1,98 @@
+//! Problems found in query and schema files, rendered with a source snippet.
+
+use std::fmt;
+
+/// How serious a diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    /// The file can't be generated.
+    Error,
+    /// Probably a mistake, but the file is still generated.
+    Warning,
+    /// Explains the diagnostic it is attached to.
+    Note
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Error => f.write_str("error"),
+            Level::Warning => f.write_str("warning"),
+            Level::Note => f.write_str("note")
+        }
+    }
+}
+
+/// A problem found in a query file, pointing at the line and column it was found at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// How serious the problem is
+    pub level: Level,
+    /// Path of the file the problem is in
+    pub path: String,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column, counted in characters
+    pub column: usize,
+    /// What is wrong
+    pub message: String,
+    /// The full source line the problem is on
+    pub snippet: String,
+    /// Other places that are part of the problem, like the first definition of a duplicate name
+    pub notes: Vec<Diagnostic>
+}
+
+impl Diagnostic {
+    /// `offset` is a byte offset into `source`, the text of the file at `path`.
+    pub fn new(path: &str, source: &str, offset: usize, message: impl Into<String>) -> Diagnostic {
+        let mut offset = offset.min(source.len());
+        while !source.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[offset..].find('\n').map_or(source.len(), |i| offset + i);
+        Diagnostic {
+            level: Level::Error,
+            path: path.to_string(),
+            line: source[..line_start].matches('\n').count() + 1,
+            column: source[line_start..offset].chars().count() + 1,
+            message: message.into(),
+            snippet: source[line_start..line_end].trim_end_matches('\r').to_string(),
+            notes: Vec::new()
+        }
+    }
+
+    /// Like `new`, for a problem that doesn't stop generation.
+    pub fn warning(path: &str, source: &str, offset: usize, message: impl Into<String>) -> Diagnostic {
+        Diagnostic { level: Level::Warning, ..Diagnostic::new(path, source, offset, message) }
+    }
+
+    /// Like `new`, for a place that explains another diagnostic.
+    pub fn note(path: &str, source: &str, offset: usize, message: impl Into<String>) -> Diagnostic {
+        Diagnostic { level: Level::Note, ..Diagnostic::new(path, source, offset, message) }
+    }
+
+    /// Attaches a note, which is rendered below the diagnostic.
+    pub fn with_note(mut self, note: Diagnostic) -> Diagnostic {
+        self.notes.push(note);
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line = self.line.to_string();
+        let gutter = " ".repeat(line.len());
+        // keep tabs so the caret lines up with the snippet
+        let padding: String = self.snippet.chars().take(self.column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        writeln!(f, "{}: {}", self.level, self.message)?;
+        writeln!(f, "{}--> {}:{}:{}", gutter, self.path, self.line, self.column)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", line, self.snippet)?;
+        write!(f, "{} | {}^", gutter, padding)?;
+        for note in &self.notes {
+            write!(f, "\n{}", note)?;
+        }
+        Ok(())
+    }
+}
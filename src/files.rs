@@ -0,0 +1,88 @@
+//! Finding the query and schema files a config selects.
+
+use std::{fs, path::{Path, PathBuf}};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::Config;
+
+const DEFAULT_INCLUDE: &str = "**/*.sql";
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        // `*` stays within one folder, `**` crosses folders
+        let glob = GlobBuilder::new(pattern).literal_separator(true).build().map_err(|e| format!("Invalid glob pattern {}: {}", pattern, e))?;
+        builder.add(glob);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Could not read directory {}: {}", dir.display(), e))?;
+    for entry in entries.flatten() {
+        if let Ok(ftype) = entry.file_type() {
+            if ftype.is_dir() {
+                walk(&entry.path(), files)?;
+            } else if ftype.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A query file, with the prefix its method and class names get.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryFile {
+    /// The path under `queries_dir`.
+    pub path: PathBuf,
+    /// The path relative to `queries_dir` without `.sql`, with `_` between folders: `economy/shop.sql` becomes `economy_shop`.
+    pub prefix: String
+}
+
+/// Finds every query file under `queries_dir` that matches the `include` patterns and none of the `exclude` patterns.
+/// Patterns are matched against the path relative to `queries_dir`, using `/` between folders.
+pub fn query_files(config: &Config) -> Result<Vec<QueryFile>, String> {
+    let include = if config.include.is_empty() {
+        glob_set(&[DEFAULT_INCLUDE.to_string()])?
+    } else {
+        glob_set(&config.include)?
+    };
+    let exclude = glob_set(&config.exclude)?;
+
+    let root = Path::new(&config.queries_dir);
+    let mut paths = Vec::new();
+    walk(root, &mut paths)?;
+    paths.sort();
+
+    let mut files = Vec::new();
+    for path in paths {
+        let relative: Vec<String> = path.strip_prefix(root).unwrap_or(&path).components().map(|c| c.as_os_str().to_string_lossy().to_string()).collect();
+        let relative = relative.join("/");
+        if !include.is_match(&relative) || exclude.is_match(&relative) {
+            continue;
+        }
+        // economy/shop.sql becomes economy_shop
+        let without_extension = relative.strip_suffix(".sql").unwrap_or(&relative);
+        files.push(QueryFile { prefix: without_extension.replace('/', "_"), path });
+    }
+    Ok(files)
+}
+
+/// The files with the `CREATE TABLE` statements of the database: `schema` itself, or every `.sql` file in it if
+/// it is a folder. Empty if no schema is configured.
+pub fn schema_files(config: &Config) -> Result<Vec<PathBuf>, String> {
+    let Some(schema) = &config.schema else {
+        return Ok(Vec::new());
+    };
+    let path = Path::new(schema);
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut paths = Vec::new();
+    walk(path, &mut paths)?;
+    paths.retain(|path| path.extension().is_some_and(|extension| extension == "sql"));
+    paths.sort();
+    Ok(paths)
+}
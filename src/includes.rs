@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+
+use crate::{columns, diagnostic::Diagnostic, schema::Schema, sqlfile::{check_fragment, Fragment, Query, SqlToken}, Dialect, ParsedFile};
+
+enum State {
+    // being expanded, so including it again is a cycle
+    Expanding,
+    // `None` if the fragment or one of its includes is broken
+    Expanded(Option<Vec<SqlToken>>)
+}
+
+struct Resolver<'a> {
+    files: &'a [ParsedFile],
+    // every fragment, with the index of the file it is declared in
+    fragments: BTreeMap<&'a str, (usize, &'a Fragment)>,
+    states: BTreeMap<&'a str, State>,
+    // the fragments that are being expanded, for the message about cycles
+    stack: Vec<&'a str>,
+    // problems, with the index of the file they are in
+    errors: Vec<(usize, Diagnostic)>
+}
+
+impl<'a> Resolver<'a> {
+    fn new(files: &'a [ParsedFile]) -> Resolver<'a> {
+        let mut resolver = Resolver { files, fragments: BTreeMap::new(), states: BTreeMap::new(), stack: Vec::new(), errors: Vec::new() };
+        for (i, file) in files.iter().enumerate() {
+            for fragment in &file.fragments {
+                match resolver.fragments.get(fragment.name.as_str()) {
+                    Some((first_file, first)) => {
+                        let first_file = &files[*first_file];
+                        let error = Diagnostic::new(&file.path, &file.source, fragment.text.header, format!("duplicate fragment name `{}`", fragment.name))
+                            .with_note(Diagnostic::note(&first_file.path, &first_file.source, first.text.header, "first defined here"));
+                        resolver.errors.push((i, error));
+                    }
+                    None => {
+                        resolver.fragments.insert(&fragment.name, (i, fragment));
+                    }
+                }
+            }
+        }
+        resolver
+    }
+
+    fn error(&mut self, file: usize, offset: usize, message: String) {
+        let diagnostic = Diagnostic::new(&self.files[file].path, &self.files[file].source, offset, message);
+        self.errors.push((file, diagnostic));
+    }
+
+    // Replaces the includes in tokens from `file`. Returns `None` if any of them is broken.
+    fn expand(&mut self, file: usize, tokens: &'a [SqlToken]) -> Option<Vec<SqlToken>> {
+        let mut out = Vec::new();
+        let mut broken = false;
+        for token in tokens {
+            match token {
+                SqlToken::Include(name, offset) => match self.include(file, name, *offset) {
+                    Some(tokens) => out.extend(tokens),
+                    None => broken = true
+                },
+                SqlToken::Optional(inner) => match self.expand(file, inner) {
+                    Some(expanded) => {
+                        // `lex_sql` couldn't check what the includes bring in, so the error points at the first one
+                        let include = inner.iter().find_map(|token| match token {
+                            SqlToken::Include(_, offset) => Some(*offset),
+                            _ => None
+                        });
+                        match include.map(|offset| (offset, check_fragment(&expanded))) {
+                            Some((offset, Err(message))) => {
+                                self.error(file, offset, message);
+                                broken = true;
+                            }
+                            _ => out.push(SqlToken::Optional(expanded))
+                        }
+                    }
+                    None => broken = true
+                },
+                _ => out.push(token.clone())
+            }
+        }
+        if broken { None } else { Some(out) }
+    }
+
+    // The tokens of the fragment `name`, which is included at `offset` in `file`.
+    fn include(&mut self, file: usize, name: &'a str, offset: usize) -> Option<Vec<SqlToken>> {
+        match self.states.get(name) {
+            Some(State::Expanded(tokens)) => tokens.clone(),
+            Some(State::Expanding) => {
+                let start = self.stack.iter().position(|expanding| *expanding == name).unwrap_or(0);
+                let cycle: Vec<String> = self.stack[start..].iter().chain([&name]).map(|name| format!("`{}`", name)).collect();
+                self.error(file, offset, format!("include cycle: {}", cycle.join(" includes ")));
+                None
+            }
+            None if self.fragments.contains_key(name) => self.expand_fragment(name),
+            None => {
+                self.error(file, offset, format!("unknown fragment `{}`", name));
+                None
+            }
+        }
+    }
+
+    fn expand_fragment(&mut self, name: &'a str) -> Option<Vec<SqlToken>> {
+        let (file, fragment) = self.fragments[name];
+        self.states.insert(name, State::Expanding);
+        self.stack.push(name);
+        let tokens = self.expand(file, &fragment.tokens);
+        self.stack.pop();
+        self.states.insert(name, State::Expanded(tokens.clone()));
+        tokens
+    }
+}
+
+// Resolves the includes of every unresolved query and moves it to the queries of its file.
+pub fn resolve(files: &mut [ParsedFile], dialect: Dialect, schema: Option<&Schema>) {
+    let mut resolved = Vec::new();
+    let errors = {
+        let mut resolver = Resolver::new(files);
+        // fragments nobody includes yet are checked too
+        let names: Vec<&str> = resolver.fragments.keys().copied().collect();
+        for name in names {
+            if !resolver.states.contains_key(name) {
+                resolver.expand_fragment(name);
+            }
+        }
+        for (i, file) in files.iter().enumerate() {
+            for query in &file.unresolved {
+                if let Some(tokens) = resolver.expand(i, &query.tokens) {
+                    let tokens = match schema {
+                        Some(schema) => columns::infer_types(schema, &file.source, &query.text, tokens, dialect),
+                        None => Ok(tokens)
+                    };
+                    resolved.push((i, tokens.and_then(|tokens| Query::new(&file.source, query.name.clone(), query.text.clone(), tokens))));
+                }
+            }
+        }
+        resolver.errors
+    };
+
+    for (i, query) in resolved {
+        match query {
+            Ok(query) => files[i].queries.push(query),
+            Err(diagnostic) => files[i].diagnostics.push(diagnostic)
+        }
+    }
+    for (i, diagnostic) in errors {
+        files[i].diagnostics.push(diagnostic);
+    }
+    for file in files {
+        file.unresolved.clear();
+        file.queries.sort_by_key(|query| query.text.header);
+        file.diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    }
+}
@@ -0,0 +1,336 @@
+//! Code generation for type-safe PDO queries.
+//!
+//! Query files are SQL with `--#name` headers, `$variable: type` parameters and `@column: type` return values.
+//! This crate turns them into a zero-dependency PHP file. The `safe_sql` binary is a thin wrapper around it;
+//! to embed the generator in your own tooling:
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! let config = safe_sql::Config::load(Path::new("safe_sql.toml"))?;
+//! let files = safe_sql::read_queries(&config)?;
+//! let php = safe_sql::generate(&config, &files)?;
+//! std::fs::write(&config.out, php).unwrap();
+//! # Ok::<(), safe_sql::Error>(())
+//! ```
+//!
+//! Single files can be parsed with [`parse_file`], which returns the typed [`Query`] model and every
+//! [`Diagnostic`] found in the file.
+
+#![warn(missing_docs)]
+
+use std::{fmt, fs, path::Path};
+
+use serde::Deserialize;
+
+mod columns;
+pub mod diagnostic;
+pub mod files;
+mod includes;
+mod names;
+mod php;
+mod php_lib;
+pub mod schema;
+mod sqlfile;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+pub use diagnostic::{Diagnostic, Level};
+pub use schema::Schema;
+pub use sqlfile::{Cardinality, Fragment, Query, QueryText, SqlToken};
+
+/// The settings from `safe_sql.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Config {
+    /// Path of the generated PHP file.
+    pub out: String,
+    /// Folder that is searched for query files.
+    pub queries_dir: String,
+    /// PHP namespace of the generated file.
+    pub namespace: String,
+    /// Glob patterns, relative to `queries_dir`, of the query files to compile. Defaults to `**/*.sql`.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Glob patterns, relative to `queries_dir`, of query files to skip.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// The SQL dialect the queries are written in.
+    #[serde(default)]
+    pub dialect: Dialect,
+    /// How variables are passed to PDO.
+    #[serde(default)]
+    pub placeholders: Placeholders,
+    /// A SQL file, or folder of SQL files, with the `CREATE TABLE` statements of the database. The types of
+    /// variables and return values are checked against it.
+    #[serde(default)]
+    pub schema: Option<String>
+}
+
+/// The kind of placeholders variables are replaced with in the generated SQL.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Placeholders {
+    /// `?`, with one argument for every time a variable is used.
+    #[default]
+    Positional,
+    /// `:name`, or `:name__1` and so on when a variable is used again.
+    Named
+}
+
+/// The SQL dialect of the query files, which decides how quotes inside strings are escaped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    /// Both `'it\'s'` and `'it''s'`. Used when no dialect is set.
+    #[default]
+    Any,
+    /// `'it\'s'`, with `\` escaping the next character in strings.
+    MySql,
+    /// `'it''s'`, with `\` being an ordinary character.
+    Sqlite,
+    /// `'it''s'`, with `\` being an ordinary character.
+    Postgres
+}
+
+impl Dialect {
+    /// Whether a backslash escapes the next character inside `quote`. Doubling a quote escapes it in every dialect.
+    pub fn backslash_escapes(self, quote: char) -> bool {
+        // MySQL doesn't escape inside backtick quoted identifiers
+        quote != '`' && matches!(self, Dialect::Any | Dialect::MySql)
+    }
+}
+
+impl Config {
+    /// Reads and parses a config file.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        if !path.exists() {
+            return Err(Error::Config(format!("Config file {} not found", path.display())))
+        }
+
+        let text = fs::read_to_string(path).map_err(|e| Error::Io(format!("Could not read {}: {}", path.display(), e)))?;
+        toml::from_str(&text).map_err(|e| Error::Config(format!("Invalid config file {}: {}", path.display(), e)))
+    }
+}
+
+/// Everything that can stop the generator.
+#[derive(Debug)]
+pub enum Error {
+    /// The config file is missing or invalid.
+    Config(String),
+    /// A file or folder could not be read.
+    Io(String),
+    /// Problems in the query files, in the order they were found.
+    Queries(Vec<Diagnostic>)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message) | Error::Io(message) => f.write_str(message),
+            Error::Queries(diagnostics) => {
+                for diagnostic in diagnostics {
+                    writeln!(f, "{}\n", diagnostic)?;
+                }
+                write!(f, "Found {} problem(s)", diagnostics.len())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A lexed query file.
+#[derive(Debug, Clone)]
+pub struct ParsedFile {
+    /// Path used in diagnostics.
+    pub path: String,
+    /// The text of the file.
+    pub source: String,
+    /// The queries that lexed without problems, in the order they are defined in.
+    pub queries: Vec<Query>,
+    /// The `--#fragment`s declared in the file.
+    pub fragments: Vec<Fragment>,
+    // queries that include fragments, until `resolve_includes` moves them to `queries`
+    pub(crate) unresolved: Vec<Fragment>,
+    /// Every problem found in the file, sorted by line and column. Broken queries are left out of `queries`.
+    pub diagnostics: Vec<Diagnostic>
+}
+
+/// Lexes the text of one query file. `prefix` is put in front of every query name, so the query `insert`
+/// with the prefix `books` becomes `books_insert`. Queries that include fragments need [`resolve_includes`].
+/// With a schema, bare `$variable`s and `@column`s get their types from the columns they refer to.
+pub fn parse_file(path: &str, prefix: &str, source: &str, dialect: Dialect, schema: Option<&Schema>) -> ParsedFile {
+    let mut diagnostics = Vec::new();
+    let things = sqlfile::lex(path, source, dialect, &mut diagnostics);
+    let base = sqlfile::lex_2(path, source, prefix.to_string(), things, Default::default(), &mut diagnostics);
+    let mut queries = Vec::new();
+    let mut fragments = Vec::new();
+    let mut unresolved = Vec::new();
+    for ((_, name), query) in base {
+        let tokens = match sqlfile::lex_sql(source, &query, dialect, schema) {
+            Ok(tokens) => tokens,
+            Err(diagnostic) => {
+                diagnostics.push(diagnostic);
+                continue;
+            }
+        };
+        if query.fragment {
+            fragments.push(Fragment { name, text: query, tokens });
+        } else if sqlfile::has_includes(&tokens) {
+            unresolved.push(Fragment { name, text: query, tokens });
+        } else {
+            let tokens = match schema {
+                Some(schema) => match columns::infer_types(schema, source, &query, tokens, dialect) {
+                    Ok(tokens) => tokens,
+                    Err(diagnostic) => {
+                        diagnostics.push(diagnostic);
+                        continue;
+                    }
+                },
+                None => tokens
+            };
+            match Query::new(source, name, query, tokens) {
+                Ok(query) => queries.push(query),
+                Err(diagnostic) => diagnostics.push(diagnostic)
+            }
+        }
+    }
+    queries.sort_by_key(|query| query.text.header);
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    ParsedFile { path: path.to_string(), source: source.to_string(), queries, fragments, unresolved, diagnostics }
+}
+
+/// Replaces the `{{name}}` includes in the files' queries with the tokens of the fragments they name, which
+/// can be declared in any of the files. Unknown fragments and include cycles are added to the diagnostics.
+/// The schema the files were parsed with infers the types the fragments left out.
+pub fn resolve_includes(files: &mut [ParsedFile], dialect: Dialect, schema: Option<&Schema>) {
+    includes::resolve(files, dialect, schema);
+}
+
+/// Checks the types of the files' variables and return values against the columns they refer to. Problems are
+/// added to the diagnostics, as warnings if the generated code still works for most values.
+pub fn check_types(files: &mut [ParsedFile], schema: &Schema, dialect: Dialect) {
+    columns::check(schema, files, dialect);
+}
+
+/// Reads the schema files selected by the config, see [`files::schema_files`]. Returns `None` if no schema is
+/// configured, and fails with [`Error::Queries`] if a `CREATE TABLE` statement can't be read.
+pub fn load_schema(config: &Config) -> Result<Option<Schema>, Error> {
+    if config.schema.is_none() {
+        return Ok(None);
+    }
+    let mut schema = Schema::default();
+    let mut diagnostics = Vec::new();
+    for path in files::schema_files(config).map_err(Error::Config)? {
+        let path = path.display().to_string();
+        let source = fs::read_to_string(&path).map_err(|e| Error::Io(format!("Could not read {}: {}", path, e)))?;
+        schema.parse_file(&path, &source, config.dialect, &mut diagnostics);
+    }
+    if !diagnostics.is_empty() {
+        return Err(Error::Queries(diagnostics));
+    }
+    Ok(Some(schema))
+}
+
+/// Reads and lexes every query file selected by the config, see [`files::query_files`], and checks them against
+/// the schema if one is configured.
+pub fn read_queries(config: &Config) -> Result<Vec<ParsedFile>, Error> {
+    let schema = load_schema(config)?;
+    let mut parsed = Vec::new();
+    for file in files::query_files(config).map_err(Error::Config)? {
+        let path = file.path.display().to_string();
+        let source = fs::read_to_string(&file.path).map_err(|e| Error::Io(format!("Could not read {}: {}", path, e)))?;
+        parsed.push(parse_file(&path, &file.prefix, &source, config.dialect, schema.as_ref()));
+    }
+    resolve_includes(&mut parsed, config.dialect, schema.as_ref());
+    if let Some(schema) = &schema {
+        check_types(&mut parsed, schema, config.dialect);
+    }
+    Ok(parsed)
+}
+
+/// Returns every problem in the given files, including queries whose generated names clash. Warnings don't stop
+/// generation.
+pub fn check(files: &[ParsedFile]) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = files.iter().flat_map(|file| file.diagnostics.iter().cloned()).collect();
+    diagnostics.extend(names::check_names(files));
+    diagnostics
+}
+
+/// What a [`Section`] of the generated file declares.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SectionKind {
+    /// `base.php` and the start or end of the `Transaction` class.
+    Header,
+    /// A query method of `Transaction`.
+    Method,
+    /// A row class or `AT_` class.
+    Class
+}
+
+impl fmt::Display for SectionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SectionKind::Header => f.write_str("header"),
+            SectionKind::Method => f.write_str("method"),
+            SectionKind::Class => f.write_str("class")
+        }
+    }
+}
+
+/// One named piece of the generated file: the header, a query method or a class.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    /// What the code declares.
+    pub kind: SectionKind,
+    /// The name of the method or class, or `Transaction` for the header.
+    pub name: String,
+    /// The PHP code, which the sections are joined into without separators.
+    pub code: String
+}
+
+/// Generates the PHP file in pieces. Queries are sorted by name so the output is the same on every run.
+/// Fails with [`Error::Queries`] if [`check`] finds any error.
+pub fn generate_sections(config: &Config, files: &[ParsedFile]) -> Result<Vec<Section>, Error> {
+
+    let diagnostics = check(files);
+    if diagnostics.iter().any(|diagnostic| diagnostic.level == Level::Error) {
+        return Err(Error::Queries(diagnostics));
+    }
+
+    let mut queries: Vec<&Query> = files.iter().flat_map(|file| &file.queries).collect();
+    queries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let ns = "namespace ".to_owned() + &config.namespace + ";";
+
+    let mut sections = vec![Section {
+        kind: SectionKind::Header,
+        name: "Transaction".to_string(),
+        code: include_str!("../base.php").replace("//%%NAMESPACE%%", &ns) + "class Transaction extends TransactionBase {"
+    }];
+
+    for query in &queries {
+        sections.push(Section { kind: SectionKind::Method, name: query.name.clone(), code: php::generate_method(&query.name, &query.tokens, query.cardinality, &query.doc, config.placeholders).to_string() });
+    }
+
+    sections.push(Section { kind: SectionKind::Header, name: "Transaction".to_string(), code: "}".to_string() });
+
+    for query in &queries {
+        let code = php::generate_return_type(&query.name, &query.tokens, query.cardinality, &query.doc);
+        if !code.is_empty() {
+            sections.push(Section { kind: SectionKind::Class, name: query.name.clone(), code });
+        }
+    }
+
+    for query in &queries {
+        let class = php::generate_async_transaction(&query.name, &query.tokens, query.cardinality);
+        sections.push(Section { kind: SectionKind::Class, name: class.name.clone(), code: class.to_string() });
+    }
+
+    Ok(sections)
+}
+
+/// Generates the whole PHP file.
+pub fn generate(config: &Config, files: &[ParsedFile]) -> Result<String, Error> {
+    Ok(generate_sections(config, files)?.into_iter().map(|section| section.code).collect())
+}
@@ -1,98 +1,110 @@
-use std::{collections::HashMap, env, fs, io, path::Path, process::exit};
+use std::{env, fs, path::Path, process::exit};
 
-use sqlfile::{SqlToken, lex_sql};
-mod sqlfile;
-mod php;
-mod php_lib;
+use safe_sql::{check, generate, read_queries, Config, Diagnostic, Error, Level, ParsedFile};
 
-use serde::Deserialize;
+mod verify;
+mod watch;
 
-#[derive(Deserialize)]
-struct Config {
-    out: String,
-    queries_dir: String,
-    namespace: String
+fn fail(error: Error) -> ! {
+    println!("{}", error);
+    exit(1);
 }
 
-fn get_config() -> Result<Config, String> {
-    let mut args = env::args();
-    let _ = args.next();
-    let arg = args.next();
-    let path;
-    let a: String;
-    if let Some(arg) = arg {
-        a = arg;
-        path = Path::new(&a);
-    } else {
-        path = Path::new("safe_sql.toml");
-    }
-    if !path.exists() {
-        return Err(format!("Config file {} not found", path.as_os_str().to_str().unwrap()))
-    }
-    
-    println!("{:?}", path);
+// Prepares every query against SQLite: the schema loaded into memory for `--sqlite`, or an existing database for
+// `--sqlite=path`.
+#[cfg(feature = "sqlite")]
+fn verify_sqlite(config: &Config, files: &[ParsedFile], flag: &str) -> Vec<Diagnostic> {
+    use safe_sql::{load_schema, sqlite};
+
+    let connection = match flag.strip_prefix("--sqlite=") {
+        Some(path) => sqlite::open(path),
+        None => match load_schema(config) {
+            Ok(Some(schema)) => sqlite::in_memory(&schema),
+            Ok(None) => Err(Error::Config("--sqlite needs a schema in the config file, or a database like --sqlite=test.db".to_string())),
+            Err(e) => Err(e)
+        }
+    };
+    sqlite::verify(&connection.unwrap_or_else(|e| fail(e)), files, config.schema.is_none())
+}
 
-    Ok(toml::from_str(&fs::read_to_string(path).unwrap()).unwrap())
-    
+#[cfg(not(feature = "sqlite"))]
+fn verify_sqlite(_config: &Config, _files: &[ParsedFile], _flag: &str) -> Vec<Diagnostic> {
+    fail(Error::Config("--sqlite needs safe_sql to be built with the `sqlite` feature".to_string()))
 }
 
-fn main() -> io::Result<()> {
+fn main() {
 
-    let config = match get_config() {
-        Err(str) => {
-            println!("{}", str);
-            exit(1);
-        }
-        Ok(t) => t
+    let (flags, mut args): (Vec<String>, Vec<String>) = env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let command = if args.first().is_some_and(|arg| arg == "watch" || arg == "check") {
+        Some(args.remove(0))
+    } else {
+        None
     };
+    let arg = args.into_iter().next().unwrap_or("safe_sql.toml".to_string());
+    let path = Path::new(&arg);
 
-    let ns = "namespace ".to_owned() + &config.namespace + ";";
+    for flag in &flags {
+        if flag != "--verify" && flag != "--sqlite" && !flag.starts_with("--sqlite=") {
+            println!("Unknown option {}", flag);
+            exit(1);
+        }
+    }
 
-    let mut out = include_str!("../base.php").replace("//%%NAMESPACE%%", &ns) + "class Transaction extends TransactionBase {";
+    if command.as_deref() == Some("watch") {
+        watch::watch(path);
+    }
 
-    let mut base: HashMap<String, String> = HashMap::new();
+    println!("{:?}", path);
 
-    if let Ok(entries) = fs::read_dir(&config.queries_dir) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                if let Ok(ftype) = entry.file_type() {
-                    if ftype.is_file() && entry.file_name().to_str().unwrap().to_owned().ends_with(".sql") {
-                        let text = fs::read_to_string(config.queries_dir.clone() + "/" + entry.file_name().to_str().unwrap()).unwrap();
-                        base = sqlfile::lex_2(entry.file_name().to_str().to_owned().unwrap().replace(".sql", ""), sqlfile::lex(text).unwrap(), base).unwrap();
-                    }
-                }
-            }
-        }
-    }
+    let config = Config::load(path).unwrap_or_else(|e| fail(e));
 
-    let mut tokens: HashMap<String, Vec<SqlToken>> = HashMap::new();
+    let files = read_queries(&config).unwrap_or_else(|e| fail(e));
 
-    for (name, sql) in &base {
-        tokens.insert(name.to_string(), lex_sql(sql.to_string()));
-        if sql == "" {
-            panic!("Syntax error in {}", name);
-        }
-        out.push_str(&php::generate_method(&name, &tokens[name]).to_string());
+    let mut diagnostics = check(&files);
+    if let Some(flag) = flags.iter().find(|flag| flag.starts_with("--sqlite")) {
+        diagnostics.extend(verify_sqlite(&config, &files, flag));
+    }
+    if diagnostics.iter().any(|diagnostic| diagnostic.level == Level::Error) {
+        fail(Error::Queries(diagnostics));
+    }
+    for warning in &diagnostics {
+        println!("{}\n", warning);
     }
 
-    out.push('}');
+    if command.as_deref() == Some("check") {
+        println!("{} queries OK", files.iter().map(|file| file.queries.len()).sum::<usize>());
+        return;
+    }
 
-    for (name, _sql) in &base {
-        out.push_str(&php::generate_return_type(&name, &tokens[name]));
+    if flags.iter().any(|flag| flag == "--verify") {
+        match verify::verify(&config, &files) {
+            Ok(()) => println!("{} is up to date", config.out),
+            Err(errors) => {
+                for error in errors {
+                    println!("{}", error);
+                }
+                exit(1);
+            }
+        }
+        return;
     }
 
-    for (name, _sql) in &base {
-        out.push_str(&php::generate_async_transaction(&name, &tokens[name]).to_string());
+    match generate(&config, &files) {
+        Err(error) => fail(error),
+        Ok(out) => {
+            if let Err(e) = fs::write(&config.out, out) {
+                println!("Could not write {}: {}", config.out, e);
+                exit(1);
+            }
+        }
     }
 
-    fs::write(config.out, out)
-    
 }
 
 /*
     Steps:
-        read sql file
-        find variables and their types
-        connect to test db
-        generate php classes
-*/
\ No newline at end of file
+        read sql file (files.rs, sqlfile.rs)
+        find variables and their types (sqlfile.rs, and columns.rs against the schema)
+        connect to test db (sqlite.rs, with --sqlite)
+        generate php classes (php.rs)
+*/
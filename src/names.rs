@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+
+use crate::{diagnostic::Diagnostic, php, sqlfile::{Query, SqlToken}, ParsedFile};
+
+// Classes declared in base.php, plus the generated Transaction class
+const BASE_CLASSES: [&str; 8] = ["TransactionBase", "AsyncTransaction", "DatabaseThread", "DatabasePool", "DataEntry", "ClosureStore", "SafeSql", "Transaction"];
+
+// Methods every query method sits next to in Transaction
+const BASE_METHODS: [&str; 6] = ["__construct", "commit", "rollBack", "placeholders", "namedPlaceholders", "namedValues"];
+
+// Words PHP doesn't allow as class names, compared case insensitively
+const RESERVED_CLASS_NAMES: [&str; 94] = [
+    "__halt_compiler", "abstract", "and", "array", "as", "bool", "break", "callable", "case", "catch", "class", "clone",
+    "const", "continue", "declare", "default", "die", "do", "echo", "else", "elseif", "empty", "enddeclare", "endfor",
+    "endforeach", "endif", "endswitch", "endwhile", "enum", "eval", "exit", "extends", "false", "final", "finally", "float",
+    "fn", "for", "foreach", "function", "global", "goto", "if", "implements", "include", "include_once", "instanceof",
+    "insteadof", "int", "interface", "isset", "iterable", "list", "match", "mixed", "namespace", "never", "new", "null",
+    "numeric", "object", "or", "parent", "print", "private", "protected", "public", "readonly", "require", "require_once",
+    "resource", "return", "self", "static", "string", "switch", "throw", "trait", "true", "try", "unset", "use", "var",
+    "void", "while", "xor", "yield", "__class__", "__dir__", "__file__", "__function__", "__line__", "__method__", "__namespace__"
+];
+
+const NAME_RULES: &str = "PHP names can only contain letters, digits and `_`, and can't start with a digit";
+
+// PHP's grammar for names: a letter, `_` or non-ASCII character, followed by those or digits.
+fn is_identifier(name: &str) -> bool {
+    let is_name_char = |char: char| char.is_ascii_alphanumeric() || char == '_' || !char.is_ascii();
+    match name.chars().next() {
+        Some(first) if !first.is_ascii_digit() => name.chars().all(is_name_char),
+        _ => false
+    }
+}
+
+/// Checks the name of a `$variable` or return value property, which becomes a PHP variable or property. The error
+/// says what is wrong with it, to be put after the name.
+pub fn check_variable(name: &str) -> Result<(), String> {
+    if !is_identifier(name) {
+        return Err(format!("isn't a valid PHP name; {}", NAME_RULES));
+    }
+    if name == "this" {
+        return Err("is reserved by PHP".to_string());
+    }
+    Ok(())
+}
+
+// The methods and classes generated for a query, see php.rs
+fn generated_names(query: &Query) -> Vec<(&'static str, String)> {
+    let mut names = vec![("method", query.name.clone())];
+    let has_returns = query.tokens.iter().any(|token| matches!(token, SqlToken::Return(..)));
+    if has_returns && php::scalar_return(&query.tokens, query.cardinality).is_none() {
+        names.push(("class", query.name.clone()));
+    }
+    names.push(("class", "AT_".to_string() + &query.name));
+    names
+}
+
+// The row class gets a constructor parameter for every return value, so no two can become the same property.
+// lex_sql already finds these within a query's own SQL; this also finds the ones brought in by `{{includes}}`.
+fn duplicate_property(query: &Query) -> Option<String> {
+    let mut properties: BTreeMap<&String, &String> = BTreeMap::new();
+    for token in &query.tokens {
+        if let SqlToken::Return(property, expression, _) = token {
+            if let Some(first) = properties.insert(property, expression) {
+                return Some(format!("query `{}` returns `@{}` and `@{}`, which both become the property `{}`, so give one of them another name with `AS alias`", query.name, first, expression, property));
+            }
+        }
+    }
+    None
+}
+
+// Checks that every query generates valid PHP method and class names, that no two queries generate the same
+// one, and that none clash with base.php. PHP method and class names are case insensitive. Also checks that no two
+// return values of a query become the same property.
+pub fn check_names(files: &[ParsedFile]) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+    let mut taken: BTreeMap<(&str, String), (&ParsedFile, &Query, String)> = BTreeMap::new();
+
+    let at = |file: &ParsedFile, query: &Query, message: String| Diagnostic::new(&file.path, &file.source, query.text.header, message);
+    let note_at = |file: &ParsedFile, query: &Query, message: String| Diagnostic::note(&file.path, &file.source, query.text.header, message);
+
+    for file in files {
+        'queries: for query in &file.queries {
+            for (kind, name) in generated_names(query) {
+                let lower = name.to_lowercase();
+                if !is_identifier(&name) {
+                    errors.push(at(file, query, format!("query `{}` generates the {} `{}`, which isn't a valid PHP name; {}", query.name, kind, name, NAME_RULES)));
+                    continue 'queries;
+                }
+                if kind == "class" && RESERVED_CLASS_NAMES.contains(&lower.as_str()) {
+                    errors.push(at(file, query, format!("query `{}` generates the class `{}`, but `{}` is a reserved word in PHP", query.name, name, name)));
+                    continue 'queries;
+                }
+                if kind == "method" && lower.starts_with("__") {
+                    errors.push(at(file, query, format!("query `{}` generates the method `{}`, but names starting with `__` are reserved by PHP", query.name, name)));
+                    continue 'queries;
+                }
+                if let Some(member) = BASE_METHODS.iter().find(|member| kind == "method" && member.to_lowercase() == lower) {
+                    errors.push(at(file, query, format!("query `{}` generates the method `{}`, which clashes with `TransactionBase::{}`", query.name, name, member)));
+                    continue 'queries;
+                }
+                if let Some(class) = BASE_CLASSES.iter().find(|class| kind == "class" && class.to_lowercase() == lower) {
+                    errors.push(at(file, query, format!("query `{}` generates the class `{}`, which clashes with the class `{}` in the generated file", query.name, name, class)));
+                    continue 'queries;
+                }
+                if let Some((first_file, first, first_name)) = taken.get(&(kind, lower.clone())) {
+                    let error = if first.name == query.name {
+                        at(file, query, format!("duplicate query name `{}`", query.name))
+                    } else {
+                        at(file, query, format!("query `{}` generates the {} `{}`, which clashes with `{}` from query `{}`", query.name, kind, name, first_name, first.name))
+                    };
+                    errors.push(error.with_note(note_at(first_file, first, format!("query `{}` is defined here", first.name))));
+                    continue 'queries;
+                }
+            }
+            for (kind, name) in generated_names(query) {
+                taken.insert((kind, name.to_lowercase()), (file, query, name));
+            }
+            if let Some(message) = duplicate_property(query) {
+                errors.push(at(file, query, message));
+            }
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{check, parse_file, resolve_includes, Dialect};
+
+    #[test]
+    fn included_return_with_the_same_property() {
+        let source = "--#fragment a_columns\n@a.Id: int, @a.Name: string;\n--#both\nSELECT {{a_columns}}, @b.Id: int FROM a JOIN b ON a.Id = b.Id;\n";
+        let mut files = vec![parse_file("test.sql", "test", source, Dialect::Any, None)];
+        resolve_includes(&mut files, Dialect::Any, None);
+        let errors = check(&files);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].message, "query `test_both` returns `@a.Id` and `@b.Id`, which both become the property `Id`, so give one of them another name with `AS alias`");
+        assert_eq!((errors[0].line, errors[0].column), (3, 1));
+    }
+}
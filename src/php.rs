@@ -1,30 +1,85 @@
 use indexmap::IndexMap;
-use crate::sqlfile::SqlToken;
+use crate::sqlfile::{flatten, Cardinality, SqlToken};
+use crate::Placeholders;
 use cascade::cascade;
 use crate::php_lib::{Class, Visibility, Function, Param, ClassMember};
 
-pub fn generate_return_type(class: &String, query: &Vec<SqlToken>) -> String {
-    let mut has_returns = false;
-    for token in query.clone() {
-        match token {
-            SqlToken::Return(_, _) => {
-                has_returns = true;
-                break;
+/// The only return value of a `:one` query, which is returned as is instead of in a row class.
+pub fn scalar_return(query: &[SqlToken], cardinality: Cardinality) -> Option<(&String, &String)> {
+    let mut returns = query.iter().filter_map(|token| match token {
+        SqlToken::Return(name, _, type_name) => Some((name, type_name)),
+        _ => None
+    });
+    match (cardinality, returns.next(), returns.next()) {
+        (Cardinality::One, Some(only), None) => Some(only),
+        _ => None
+    }
+}
+
+/// Whether a parameter type is an array like `int[]`, which is expanded into one placeholder for each value.
+pub fn is_array(type_name: &str) -> bool {
+    type_name.ends_with("[]")
+}
+
+// The type of a parameter in PHP, which only knows that `int[]` is an `array`.
+fn param_type(type_name: &str) -> String {
+    if is_array(type_name) { "array".to_string() } else { type_name.to_string() }
+}
+
+// Makes a PHP type accept null: `int` becomes `?int` and `int|string` becomes `int|string|null`.
+fn nullable(type_name: &str) -> String {
+    let lower = type_name.to_lowercase();
+    if type_name.starts_with('?') || lower == "mixed" || lower == "null" || lower.split('|').any(|part| part == "null") {
+        type_name.to_string()
+    } else if type_name.contains('|') {
+        type_name.to_string() + "|null"
+    } else {
+        "?".to_string() + type_name
+    }
+}
+
+/// Renders a PHPDoc comment, with the description before the `@` tags. Single lines are kept on one line,
+/// like `/** @return int */`.
+pub fn phpdoc(lines: &[String]) -> String {
+    let (mut lines, tags): (Vec<String>, Vec<String>) = lines.iter().cloned().partition(|line| !line.starts_with('@'));
+    if !lines.is_empty() && !tags.is_empty() {
+        lines.push("".to_string());
+    }
+    lines.extend(tags);
+    // `*/` would end the comment early
+    let lines: Vec<String> = lines.iter().map(|line| line.replace("*/", "*\\/")).collect();
+    match lines.as_slice() {
+        [] => "".to_string(),
+        [line] => "/** ".to_string() + line + " */",
+        _ => {
+            let mut out = "/**\n".to_string();
+            for line in &lines {
+                out.push_str((" * ".to_string() + line).trim_end());
+                out.push('\n');
             }
-            _ => {}
+            out + " */\n"
         }
     }
+}
 
-    if !has_returns {return "".to_string()}
+/// Generates the class a query's rows are returned as, or an empty string if the query returns nothing
+/// or returns a single value, see [`scalar_return`]. The class gets the query's description and `@deprecated` tag.
+pub fn generate_return_type(class: &str, query: &[SqlToken], cardinality: Cardinality, doc: &[String]) -> String {
+    let mut has_returns = false;
+    for token in query {
+        if let SqlToken::Return(..) = token {
+            has_returns = true;
+            break;
+        }
+    }
+
+    if !has_returns || scalar_return(query, cardinality).is_some() {return "".to_string()}
 
     let mut params: Vec<Param> = Vec::new();
 
     for token in query {
-        match token {
-            SqlToken::Return(name, type_name) => {
-                params.push(Param { name: name.to_string(), param_type: type_name.to_string(), visibility: Some(Visibility::Public()) })
-            }
-            _ => {}
+        if let SqlToken::Return(name, _, type_name) = token {
+            params.push(Param { name: name.to_string(), param_type: type_name.to_string(), visibility: Some(Visibility::Public()) })
         }
     }
 
@@ -33,7 +88,8 @@ pub fn generate_return_type(class: &String, query: &Vec<SqlToken>) -> String {
         name: "__construct".to_string(),
         params,
         comment: "".to_string(),
-        visibility: Some(Visibility::Public())
+        visibility: Some(Visibility::Public()),
+        return_type: None
     };
 
     let members: Vec<Box<dyn ClassMember>> = vec![Box::new(constructor)];
@@ -44,95 +100,170 @@ pub fn generate_return_type(class: &String, query: &Vec<SqlToken>) -> String {
         implements: Vec::new(),
         is_abstract: false,
         members,
-        comment: "".to_string()
+        comment: phpdoc(&doc.iter().filter(|line| !line.starts_with("@param") && !line.starts_with("@throws")).cloned().collect::<Vec<_>>())
     }.to_string()
 }
 
-pub fn generate_method<'a>(name: &String, query: &Vec<SqlToken>) -> Function {
-    let mut has_returns = false;
-    for token in query.clone() {
-        match token {
-            SqlToken::Return(_, _) => {
-                has_returns = true;
-                break;
-            }
-            _ => {}
-        }
+// The SQL of a query as the pieces of a PHP expression, and the arguments for its placeholders.
+// The SQL is only built at runtime for array parameters and optional fragments, otherwise it is a single string.
+struct Statement {
+    pieces: Vec<String>,
+    // elements of the arguments array
+    args: Vec<String>,
+    // arrays of named arguments that are added to the arguments array
+    merged: Vec<String>
+}
+
+// Some PDO drivers don't allow a named placeholder twice in one query, so every use of a variable gets its own:
+// `:v`, then `:v__1`, `:v__2` and so on. `uses` counts the placeholders each variable has so far.
+fn named_placeholder(uses: &mut IndexMap<String, usize>, name: &str) -> String {
+    let count = uses.entry(name.to_string()).or_insert(0);
+    let placeholder = if *count == 0 { name.to_string() } else { format!("{}__{}", name, count) };
+    *count += 1;
+    placeholder
+}
+
+impl Statement {
+    fn new(query: &[SqlToken], placeholders: Placeholders) -> Statement {
+        Statement::build(query, placeholders, &mut IndexMap::new())
     }
-    let comment = if has_returns {
-        "/** @return ".to_string() + &name + "[]|\\Generator */"
-    } else {
-        "/** @return int */".to_string()
-    };
-    
-    let mut vars: Vec<String> = Vec::new();
-    let mut params: Vec<Param> = Vec::new();
-    'outer: for token in query.clone() {
-        match token {
-            SqlToken::Variable(name, type_name) => {
-                for param in &params {
-                    if param.name == name {
-                        vars.push(name);
-                        continue 'outer;
+
+    // Optional fragments are built with the same `uses` as the query around them, so their placeholders don't clash.
+    fn build(query: &[SqlToken], placeholders: Placeholders, uses: &mut IndexMap<String, usize>) -> Statement {
+        let named = placeholders == Placeholders::Named;
+        let literal = |sql: &str| "\"".to_string() + &escape(sql.to_string()) + "\"";
+        let mut statement = Statement { pieces: Vec::new(), args: Vec::new(), merged: Vec::new() };
+        let mut q_marked = "".to_string();
+        for token in query {
+            match token {
+                SqlToken::Sql(sql) | SqlToken::Return(_, sql, _) => q_marked.push_str(sql),
+                SqlToken::Include(_, _) => unreachable!("includes are resolved before generation"),
+                SqlToken::Variable(name, type_name) if is_array(type_name) => {
+                    if !q_marked.is_empty() {
+                        statement.pieces.push(literal(&q_marked));
+                        q_marked = "".to_string();
+                    }
+                    if named {
+                        let placeholder = named_placeholder(uses, name);
+                        statement.pieces.push("self::namedPlaceholders(\"".to_string() + &placeholder + "\", $" + name + ")");
+                        statement.merged.push("self::namedValues(\"".to_string() + &placeholder + "\", $" + name + ")");
+                    } else {
+                        statement.pieces.push("self::placeholders($".to_string() + name + ")");
+                        // the values are spread into the arguments, one for each placeholder
+                        statement.args.push("...array_values($".to_string() + name + ")");
+                    }
+                }
+                SqlToken::Variable(name, _) if named => {
+                    let placeholder = named_placeholder(uses, name);
+                    q_marked.push_str(&(":".to_string() + &placeholder));
+                    statement.args.push("'".to_string() + &placeholder + "' => $" + name);
+                }
+                SqlToken::Variable(name, _) => {
+                    q_marked.push('?');
+                    statement.args.push("$".to_string() + name);
+                }
+                SqlToken::Optional(fragment) => {
+                    if !q_marked.is_empty() {
+                        statement.pieces.push(literal(&q_marked));
+                        q_marked = "".to_string();
+                    }
+                    let mut names: Vec<&String> = Vec::new();
+                    for token in fragment {
+                        if let SqlToken::Variable(name, _) = token {
+                            if !names.contains(&name) {
+                                names.push(name);
+                            }
+                        }
+                    }
+                    let condition = names.iter().map(|name| "$".to_string() + name + " !== null").collect::<Vec<_>>().join(" && ");
+                    let inner = Statement::build(fragment, placeholders, uses);
+                    statement.pieces.push(format!("({} ? {} : \"\")", condition, inner.pieces.join(" . ")));
+                    if named {
+                        statement.merged.push(format!("({} ? {} : [])", condition, inner.args()));
+                    } else {
+                        statement.args.push(format!("...({} ? [{}] : [])", condition, inner.args.join(", ")));
                     }
                 }
-                params.push(Param { name: name.clone(), param_type: type_name, visibility: None });
-                vars.push(name);
             }
-            _ => {}
         }
+        if statement.pieces.is_empty() || !q_marked.is_empty() {
+            statement.pieces.push(literal(&q_marked));
+        }
+        statement
     }
-    let mut q_marked = "".to_string();
-    let mut insert = false;
-    for (i, token) in query.clone().into_iter().enumerate() {
-        q_marked.push_str(match &token {
-            SqlToken::Return(a, _) => a,
-            SqlToken::Variable(_, _) => "?",
-            SqlToken::Sql(a) => {
-                if a.to_lowercase().starts_with("insert") && i == 0 {
-                    insert = true;
-                }
-                a
-            },
-        })
-    }
-    let mut body: String = String::new();
-    if has_returns {
-        body.push_str("$statement = $this->db->prepare(\"");
 
-        body.push_str(&escape(q_marked));
-        body.push_str(&("\"); $statement->execute(["));
-        for var in vars {
-            body.push_str(&("$".to_owned() + &var + ","));
+    // The PHP expression passed to `execute`.
+    fn args(&self) -> String {
+        let mut out = "[".to_string();
+        for arg in &self.args {
+            out.push_str(&(arg.to_string() + ","));
         }
-        body.push_str(
-            &("]); while ($res = $statement->fetch(\\PDO::FETCH_NUM)) { yield new ".to_owned()
-                + &name
-                + "(...$res);}"),
-        );
-    } else {
-        body.push_str(&("$statement = $this->db->prepare(\"".to_string() + &escape(q_marked) + "\");$statement->execute(["));
-        for var in vars {
-            body.push_str(&("$".to_owned() + &var + ","));
+        out.push(']');
+        for merged in &self.merged {
+            out.push_str(&(" + ".to_string() + merged));
         }
-        body.push_str("]);");
-        if insert {
-            body.push_str("return $this->db->lastInsertId();")
-        } else {
-            body.push_str("return $statement->rowCount();")
+        out
+    }
+}
+
+/// Generates the `Transaction` method that runs a query. `cardinality` decides what it returns, and `doc` is put
+/// in its PHPDoc above the `@return` tag. Includes must have been resolved, see [`crate::resolve_includes`].
+pub fn generate_method(name: &str, query: &[SqlToken], cardinality: Cardinality, doc: &[String], placeholders: Placeholders) -> Function {
+    let scalar = scalar_return(query, cardinality).map(|(_, type_name)| nullable(type_name));
+    let return_tag = match (cardinality, &scalar) {
+        (_, Some(type_name)) => "@return ".to_string() + type_name,
+        (Cardinality::One, None) => "@return ?".to_string() + name,
+        (Cardinality::Many, None) => "@return ".to_string() + name + "[]|\\Generator",
+        (Cardinality::Exec, None) => "@return void".to_string(),
+        (Cardinality::ExecRows, None) => "@return int".to_string(),
+        (Cardinality::LastId, None) => "@return string".to_string()
+    };
+    let mut lines = doc.to_vec();
+    
+    let mut params: Vec<Param> = Vec::new();
+    for token in flatten(query) {
+        if let SqlToken::Variable(name, type_name) = token {
+            if params.iter().any(|param| &param.name == name) {
+                continue;
+            }
+            if is_array(type_name) && !doc.iter().any(|line| line.starts_with("@param") && line.contains(&("$".to_string() + name))) {
+                // `array` doesn't tell the IDE what goes in it
+                lines.push(format!("@param {} ${}", type_name, name));
+            }
+            params.push(Param { name: name.clone(), param_type: param_type(type_name), visibility: None });
         }
     }
+    lines.push(return_tag);
+    let comment = phpdoc(&lines);
+
+    let statement = Statement::new(query, placeholders);
+    let mut body: String = String::new();
+    body.push_str("$statement = $this->db->prepare(");
+    body.push_str(&statement.pieces.join(" . "));
+    body.push_str(if cardinality.returns_rows() { "); $statement->execute(" } else { ");$statement->execute(" });
+    body.push_str(&statement.args());
+    body.push_str(");");
+    match cardinality {
+        // the declared return type converts the value like the constructor of a row class would
+        Cardinality::One if scalar.is_some() => body.push_str(" $res = $statement->fetch(\\PDO::FETCH_NUM); $statement->closeCursor(); return $res === false ? null : $res[0];"),
+        Cardinality::One => body.push_str(&(" $res = $statement->fetch(\\PDO::FETCH_NUM); $statement->closeCursor(); return $res === false ? null : new ".to_owned() + name + "(...$res);")),
+        Cardinality::Many => body.push_str(&(" while ($res = $statement->fetch(\\PDO::FETCH_NUM)) { yield new ".to_owned() + name + "(...$res);}")),
+        Cardinality::Exec => {}
+        Cardinality::ExecRows => body.push_str("return $statement->rowCount();"),
+        Cardinality::LastId => body.push_str("return $this->db->lastInsertId();")
+    }
 
-    return Function {
+    Function {
         body: vec![Box::new(body)],
-        name: name.clone(),
+        name: name.to_string(),
         params,
         comment,
-        visibility: Some(Visibility::Public())
-    };
+        visibility: Some(Visibility::Public()),
+        return_type: scalar
+    }
 }
 
-// returns for double quotes
+/// Escapes a string for use inside a double quoted PHP string.
 pub fn escape(string: String) -> String {
     let mut out = "".to_string();
     for char in string.chars() {
@@ -154,40 +285,28 @@ pub fn escape(string: String) -> String {
     out
 }
 
-pub fn generate_async_transaction(name: &String, query: &Vec<SqlToken>) -> Class {
+/// Generates the `AT_` class that runs a query through `DatabasePool`.
+pub fn generate_async_transaction(name: &str, query: &[SqlToken], cardinality: Cardinality) -> Class {
     let mut body = "$out = $t->".to_string();
 
     let mut params: IndexMap<String, Param> = IndexMap::new();
 
-    for token in query.clone() {
-        match token {
-            SqlToken::Variable(name, type_name) => {
-                params.insert(name.clone(), Param { name: name.to_string(), param_type: type_name.to_string(), visibility: Some(Visibility::Private()) });
-            }
-            _ => {}
+    for token in flatten(query) {
+        if let SqlToken::Variable(name, type_name) = token {
+            params.insert(name.clone(), Param { name: name.to_string(), param_type: param_type(type_name), visibility: Some(Visibility::Private()) });
         }
     }
 
     body.push_str(name);
-    body.push_str("(");
+    body.push('(');
     for (_, param) in &params {
         body.push_str("$this->");
         body.push_str(&param.name);
-        body.push_str(",");
+        body.push(',');
     }
 
-    let mut has_returns = false;
-    for token in query {
-        match token {
-            SqlToken::Return(_, _) => {
-                has_returns = true;
-                break;
-            }
-            _ => {}
-        }
-    }
-
-    if has_returns {
+    // a generator can't be sent back from the worker thread, so the rows are collected first
+    if cardinality == Cardinality::Many {
         body.push_str(");
             $rv = [];
             foreach ($out as $out) {
@@ -213,15 +332,103 @@ pub fn generate_async_transaction(name: &String, query: &Vec<SqlToken>) -> Class
                 params: params.values().cloned().collect(),
                 name: "__construct".to_string(),
                 body: vec![],
-                visibility: Some(Visibility::Public())
+                visibility: Some(Visibility::Public()),
+                return_type: None
             }),
             Box::new(Function {
                 comment: "".to_string(),
                 name: "run".to_string(),
                 params: vec![Param {name: "t".to_string(), param_type: "Transaction".to_string(), visibility: None}],
                 body: vec![Box::new(body)],
-                visibility: Some(Visibility::Public())
+                visibility: Some(Visibility::Public()),
+                return_type: None
             })
         ]
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_file, resolve_includes, Dialect};
+
+    // The method generated for the only query in `source`.
+    fn method(source: &str, placeholders: Placeholders) -> Function {
+        let mut files = vec![parse_file("test.sql", "test", source, Dialect::Any, None)];
+        resolve_includes(&mut files, Dialect::Any, None);
+        assert_eq!(files[0].diagnostics, Vec::new());
+        let query = &files[0].queries[0];
+        generate_method(&query.name, &query.tokens, query.cardinality, &query.doc, placeholders)
+    }
+
+    fn body(method: &Function) -> String {
+        method.body.iter().map(|thing| thing.to_string()).collect()
+    }
+
+    #[test]
+    fn one_scalar() {
+        let method = method("--#count:one\nSELECT @COUNT(*) AS n: int FROM t WHERE a = $a: string;", Placeholders::Positional);
+        assert_eq!(method.return_type.as_deref(), Some("?int"));
+        assert_eq!(
+            body(&method),
+            "$statement = $this->db->prepare(\"SELECT COUNT(*) AS n FROM t WHERE a = ?\"); $statement->execute([$a,]); \
+            $res = $statement->fetch(\\PDO::FETCH_NUM); $statement->closeCursor(); return $res === false ? null : $res[0];"
+        );
+        assert_eq!(generate_return_type(&method.name, &[SqlToken::Return("n".to_string(), "COUNT(*) AS n".to_string(), "int".to_string())], Cardinality::One, &[]), "");
+    }
+
+    #[test]
+    fn array_parameter() {
+        let method = method("--#by_ids\nSELECT @Id: int FROM t WHERE Id IN ($ids: int[]) AND a = $a: int;", Placeholders::Positional);
+        assert_eq!(method.params.iter().map(|param| param.param_type.as_str()).collect::<Vec<_>>(), vec!["array", "int"]);
+        assert!(method.comment.contains("@param int[] $ids"), "{}", method.comment);
+        assert_eq!(
+            body(&method),
+            "$statement = $this->db->prepare(\"SELECT Id FROM t WHERE Id IN (\" . self::placeholders($ids) . \") AND a = ?\"); \
+            $statement->execute([...array_values($ids),$a,]); while ($res = $statement->fetch(\\PDO::FETCH_NUM)) { yield new test_by_ids(...$res);}"
+        );
+    }
+
+    #[test]
+    fn optional_filters() {
+        let source = "--#f\nSELECT @Id: int FROM t WHERE 1 = 1 [[ AND a = $a: ?int ]] [[ AND b = $b: ?string OR c = $b: ?string ]];";
+        assert_eq!(
+            body(&method(source, Placeholders::Positional)),
+            "$statement = $this->db->prepare(\"SELECT Id FROM t WHERE 1 = 1 \" . ($a !== null ? \" AND a = ? \" : \"\") . \" \" . \
+            ($b !== null ? \" AND b = ? OR c = ? \" : \"\")); $statement->execute([...($a !== null ? [$a] : []),...($b !== null ? [$b, $b] : []),]); \
+            while ($res = $statement->fetch(\\PDO::FETCH_NUM)) { yield new test_f(...$res);}"
+        );
+    }
+
+    #[test]
+    fn named_optional_filters() {
+        let source = "--#f\nSELECT @Id: int FROM t WHERE Id > $min: int [[ AND a = $a: ?int ]] [[ AND b = $b: ?string ]];";
+        assert_eq!(
+            body(&method(source, Placeholders::Named)),
+            "$statement = $this->db->prepare(\"SELECT Id FROM t WHERE Id > :min \" . ($a !== null ? \" AND a = :a \" : \"\") . \" \" . \
+            ($b !== null ? \" AND b = :b \" : \"\")); $statement->execute(['min' => $min,] + ($a !== null ? ['a' => $a,] : []) + ($b !== null ? ['b' => $b,] : [])); \
+            while ($res = $statement->fetch(\\PDO::FETCH_NUM)) { yield new test_f(...$res);}"
+        );
+    }
+
+    #[test]
+    fn repeated_named_variable() {
+        let source = "--#f\nSELECT @Id: int FROM t WHERE c = $a: ?int [[ AND (a = $a: ?int OR b = $a: ?int) ]];";
+        assert_eq!(
+            body(&method(source, Placeholders::Named)),
+            "$statement = $this->db->prepare(\"SELECT Id FROM t WHERE c = :a \" . ($a !== null ? \" AND (a = :a__1 OR b = :a__2) \" : \"\")); \
+            $statement->execute(['a' => $a,] + ($a !== null ? ['a__1' => $a,'a__2' => $a,] : [])); \
+            while ($res = $statement->fetch(\\PDO::FETCH_NUM)) { yield new test_f(...$res);}"
+        );
+    }
+
+    #[test]
+    fn repeated_named_array() {
+        let method = method("--#f:exec\nDELETE FROM t WHERE a IN ($ids: int[]) OR b IN ($ids: int[]);", Placeholders::Named);
+        assert_eq!(method.params.len(), 1);
+        assert_eq!(
+            body(&method),
+            "$statement = $this->db->prepare(\"DELETE FROM t WHERE a IN (\" . self::namedPlaceholders(\"ids\", $ids) . \") OR b IN (\" . \
+            self::namedPlaceholders(\"ids__1\", $ids) . \")\");$statement->execute([] + self::namedValues(\"ids\", $ids) + self::namedValues(\"ids__1\", $ids));"
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use string_builder::Builder;
 
 pub struct Class {
@@ -14,46 +16,41 @@ pub struct Class {
 pub trait ClassMember: ToString {}
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub enum Visibility {
     Public(),
     Private(),
     Protected()
 }
 
-impl ToString for Visibility {
-    fn to_string(&self) -> String {
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Visibility::Public() => "public ".to_string(),
-            Visibility::Private() => "private ".to_string(),
-            Visibility::Protected() => "protected ".to_string(),
+            Visibility::Public() => f.write_str("public "),
+            Visibility::Private() => f.write_str("private "),
+            Visibility::Protected() => f.write_str("protected "),
         }
     }
 }
 
-impl ToString for Class {
-    fn to_string(&self) -> String {
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut b = Builder::default();
+        b.append(self.comment.as_str());
         if self.is_abstract {
             b.append("abstract ");
         }
         b.append("class ");
         b.append(self.name.as_str());
         b.append(" ");
-        match &self.extends {
-            Some(extends) => {
-                b.append("extends ");
-                b.append(extends.as_str());
-                b.append(" ");
-            }
-            None => {}
+        if let Some(extends) = &self.extends {
+            b.append("extends ");
+            b.append(extends.as_str());
+            b.append(" ");
         }
         if !self.implements.is_empty() {
             b.append("implements ");
-            let mut len = 0;
-            for _ in &self.implements {
-                len+=1;
-            }
-            println!("{}", len);
+            let mut len = self.implements.len();
             for implement in &self.implements {
                 len -= 1;
                 b.append(implement.as_str());
@@ -69,7 +66,7 @@ impl ToString for Class {
             b.append(member.to_string());
         }
         b.append("}");
-        b.string().unwrap()
+        f.write_str(&b.string().unwrap())
     }
 }
 
@@ -79,19 +76,19 @@ pub struct Function {
     pub params: Vec<Param>,
     pub body: Vec<Box<dyn ToString>>,
     pub visibility: Option<Visibility>,
-    pub comment: String
+    pub comment: String,
+    pub return_type: Option<String>
 
 }
 
-impl ToString for Function {
-    fn to_string(&self) -> String {
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut b = Builder::default();
 
         b.append(self.comment.as_str());
         
-        match &self.visibility {
-            Some(visibility) => {b.append(visibility.to_string())}
-            None => {}
+        if let Some(visibility) = &self.visibility {
+            b.append(visibility.to_string())
         }
 
         b.append("function ");
@@ -111,7 +108,14 @@ impl ToString for Function {
             b.append(",");
         }
 
-        b.append(") {");
+        b.append(")");
+
+        if let Some(return_type) = &self.return_type {
+            b.append(": ");
+            b.append(return_type.as_str());
+        }
+
+        b.append(" {");
 
         for thing in &self.body {
             b.append(thing.to_string());
@@ -120,7 +124,7 @@ impl ToString for Function {
         b.append("}");
 
 
-        b.string().unwrap()
+        f.write_str(&b.string().unwrap())
     }
 }
 
@@ -0,0 +1,441 @@
+//! The tables and columns of the database, read from `CREATE TABLE` statements.
+
+use crate::{diagnostic::Diagnostic, sqlfile::{lex, SourceText, Thing}, Dialect};
+
+/// A column from a `CREATE TABLE` statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    /// The name without quotes.
+    pub name: String,
+    /// The declared type, like `VARCHAR(255)`, or empty if it has none.
+    pub sql_type: String,
+    /// False for `NOT NULL` and primary key columns.
+    pub nullable: bool,
+    /// Byte offset of the column name in its schema file.
+    pub offset: usize
+}
+
+/// A table from a `CREATE TABLE` statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    /// The name without the schema or database in front of it.
+    pub name: String,
+    /// The columns in the order they are declared in.
+    pub columns: Vec<Column>,
+    /// Index of the file it is created in, see [`Schema::files`].
+    pub file: usize
+}
+
+impl Table {
+    /// Finds a column by name, ignoring case.
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.columns.iter().find(|column| column.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// The tables of the database, read from the files `schema` points at.
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    /// Path and text of every schema file.
+    pub files: Vec<(String, String)>,
+    /// Every table of every file, in the order they are created in.
+    pub tables: Vec<Table>
+}
+
+impl Schema {
+    /// Adds the tables created in a SQL file. Other statements are skipped, and `CREATE TABLE` statements that
+    /// can't be read are pushed to `errors`.
+    pub fn parse_file(&mut self, path: &str, source: &str, dialect: Dialect, errors: &mut Vec<Diagnostic>) {
+        let file = self.files.len();
+        self.files.push((path.to_string(), source.to_string()));
+        let mut statements = vec![SourceText::default()];
+        for thing in lex(path, source, dialect, errors) {
+            match thing {
+                Thing::Sql(text) => statements.last_mut().unwrap().push_text(&text),
+                Thing::EndOfQuery(_) => statements.push(SourceText::default()),
+                Thing::Comment(_, _) | Thing::BlockComment(_, _) => {}
+            }
+        }
+        // the last statement doesn't need a `;`
+        for statement in statements {
+            match create_table(&statement, file, dialect) {
+                Ok(Some(table)) => self.tables.push(table),
+                Ok(None) => {}
+                Err((index, message)) => errors.push(Diagnostic::new(path, source, statement.offset(index), message))
+            }
+        }
+    }
+
+    /// Whether any table has a column with this name, ignoring case.
+    pub fn has_column(&self, name: &str) -> bool {
+        self.tables.iter().any(|table| table.column(name).is_some())
+    }
+
+    /// Finds a table by name, ignoring case and any schema or database in front of it.
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        let name = name.rsplit('.').next().unwrap_or(name);
+        self.tables.iter().find(|table| table.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// A piece of SQL, for the little parsing the schema checks need.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Word {
+    Name(String), // keywords, unquoted identifiers and numbers
+    Quoted(String), // identifier in `"`, `` ` `` or `[]`, without the quotes
+    Text, // string literal
+    Symbol(char)
+}
+
+impl Word {
+    pub fn is(&self, keyword: &str) -> bool {
+        matches!(self, Word::Name(name) if name.eq_ignore_ascii_case(keyword))
+    }
+
+    pub fn is_any(&self, keywords: &[&str]) -> bool {
+        keywords.iter().any(|keyword| self.is(keyword))
+    }
+
+    pub fn identifier(&self) -> Option<&str> {
+        match self {
+            Word::Name(name) | Word::Quoted(name) => Some(name),
+            _ => None
+        }
+    }
+}
+
+/// Splits SQL without comments into words, with the character index each one starts at.
+pub(crate) fn words(sql: &str, dialect: Dialect) -> Vec<(usize, Word)> {
+    let mut words = Vec::new();
+    let mut chars = sql.chars().enumerate().peekable();
+    while let Some((i, char)) = chars.next() {
+        if char.is_whitespace() {
+            continue;
+        }
+        let close = match char {
+            '"' | '`' | '\'' => char,
+            '[' => ']',
+            _ if char.is_alphanumeric() || char == '_' || char == '$' => {
+                let mut name = char.to_string();
+                while let Some((_, char)) = chars.next_if(|(_, char)| char.is_alphanumeric() || *char == '_' || *char == '$') {
+                    name.push(char);
+                }
+                words.push((i, Word::Name(name)));
+                continue;
+            }
+            _ => {
+                words.push((i, Word::Symbol(char)));
+                continue;
+            }
+        };
+        let mut text = String::new();
+        while let Some((_, char)) = chars.next() {
+            if char == '\\' && dialect.backslash_escapes(close) {
+                chars.next();
+            } else if char == close && chars.next_if(|(_, next_char)| *next_char == close && close != ']').is_none() {
+                break;
+            }
+            text.push(char);
+        }
+        words.push((i, if char == '\'' { Word::Text } else { Word::Quoted(text) }));
+    }
+    words
+}
+
+// Words that end the type of a column and start its constraints
+const COLUMN_CONSTRAINTS: [&str; 17] = [
+    "NOT", "NULL", "PRIMARY", "UNIQUE", "DEFAULT", "REFERENCES", "CHECK", "CONSTRAINT", "AUTO_INCREMENT", "AUTOINCREMENT",
+    "COLLATE", "GENERATED", "AS", "COMMENT", "ON", "KEY", "CHARSET"
+];
+
+// Words that start a table constraint instead of a column
+const TABLE_CONSTRAINTS: [&str; 10] = ["CONSTRAINT", "PRIMARY", "UNIQUE", "FOREIGN", "CHECK", "KEY", "INDEX", "FULLTEXT", "SPATIAL", "EXCLUDE"];
+
+// Splits the words between brackets at the commas that aren't in other brackets.
+fn split_list(words: &[(usize, Word)]) -> Vec<&[(usize, Word)]> {
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, (_, word)) in words.iter().enumerate() {
+        match word {
+            Word::Symbol('(') => depth += 1,
+            Word::Symbol(')') => depth -= 1,
+            Word::Symbol(',') if depth == 0 => {
+                items.push(&words[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&words[start..]);
+    items
+}
+
+// Reads a `CREATE TABLE` statement. Returns `None` for other statements, and the index of the problem and a
+// message for statements that can't be read.
+fn create_table(statement: &SourceText, file: usize, dialect: Dialect) -> Result<Option<Table>, (usize, String)> {
+    let words = words(&statement.text, dialect);
+    let mut i = 0;
+    let mut expect = |keywords: &[&str]| {
+        let found = words.get(i).is_some_and(|(_, word)| word.is_any(keywords));
+        if found {
+            i += 1;
+        }
+        found
+    };
+    if !expect(&["CREATE"]) {
+        return Ok(None);
+    }
+    while expect(&["TEMP", "TEMPORARY", "UNLOGGED", "GLOBAL", "LOCAL"]) {}
+    if !expect(&["TABLE"]) {
+        return Ok(None);
+    }
+    if expect(&["IF"]) && !(expect(&["NOT"]) && expect(&["EXISTS"])) {
+        return Err((words[i - 1].0, "expected `IF NOT EXISTS`".to_string()));
+    }
+
+    let mut name = String::new();
+    while let Some((_, word)) = words.get(i) {
+        match word.identifier() {
+            Some(part) => name = part.to_string(),
+            None => break
+        }
+        i += 1;
+        if words.get(i).is_some_and(|(_, word)| *word == Word::Symbol('.')) {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    match words.get(i) {
+        _ if name.is_empty() => return Err((words.get(i).map_or(statement.text.chars().count(), |(index, _)| *index), "expected a table name".to_string())),
+        // `CREATE TABLE copy AS SELECT ...` and `CREATE TABLE copy LIKE original` have no column list to read
+        Some((_, word)) if word.is_any(&["AS", "LIKE"]) => return Ok(None),
+        Some((_, Word::Symbol('('))) => i += 1,
+        Some((index, _)) => return Err((*index, format!("expected `(` and the columns of `{}`", name))),
+        None => return Err((statement.text.chars().count(), format!("expected `(` and the columns of `{}`", name)))
+    }
+
+    let mut depth = 0;
+    let end = words[i..].iter().position(|(_, word)| {
+        match word {
+            Word::Symbol('(') => depth += 1,
+            Word::Symbol(')') if depth == 0 => return true,
+            Word::Symbol(')') => depth -= 1,
+            _ => {}
+        }
+        false
+    });
+    let Some(end) = end else {
+        return Err((words[i - 1].0, "expected `)` after the columns".to_string()));
+    };
+
+    let mut table = Table { name, columns: Vec::new(), file };
+    let mut primary_key = Vec::new();
+    for definition in split_list(&words[i..i + end]) {
+        let Some((index, first)) = definition.first() else {
+            continue;
+        };
+        if first.is_any(&TABLE_CONSTRAINTS) {
+            // `PRIMARY KEY (a, b)` makes its columns NOT NULL
+            if let Some(start) = definition.iter().position(|(_, word)| word.is("PRIMARY")) {
+                primary_key.extend(definition[start..].iter().filter_map(|(_, word)| word.identifier()).map(str::to_string));
+            }
+            continue;
+        }
+        let Some(name) = first.identifier() else {
+            return Err((*index, "expected a column name".to_string()));
+        };
+        let rest = &definition[1..];
+        let type_end = rest.iter().position(|(_, word)| word.is_any(&COLUMN_CONSTRAINTS)).unwrap_or(rest.len());
+        let mut sql_type = String::new();
+        for (_, word) in &rest[..type_end] {
+            match word {
+                Word::Name(name) | Word::Quoted(name) => {
+                    if sql_type.ends_with(|char: char| char.is_alphanumeric() || char == ')') {
+                        sql_type.push(' ');
+                    }
+                    sql_type.push_str(name);
+                }
+                Word::Text => sql_type.push_str("''"),
+                Word::Symbol(char) => sql_type.push(*char)
+            }
+        }
+        let constraints = &rest[type_end..];
+        let not_null = constraints.windows(2).any(|pair| pair[0].1.is("NOT") && pair[1].1.is("NULL"));
+        let primary = constraints.iter().any(|(_, word)| word.is("PRIMARY"));
+        // Postgres' serial types are integers with a sequence as the default, and are never NULL
+        let serial = ["SERIAL", "BIGSERIAL", "SMALLSERIAL"].iter().any(|serial| sql_type.eq_ignore_ascii_case(serial));
+        table.columns.push(Column { name: name.to_string(), sql_type, nullable: !(not_null || primary || serial), offset: statement.offset(*index) });
+    }
+    for column in &mut table.columns {
+        if primary_key.iter().any(|name| name.eq_ignore_ascii_case(&column.name)) {
+            column.nullable = false;
+        }
+    }
+    Ok(Some(table))
+}
+
+/// What PHP gets from PDO for a column, decided from the declared type the way SQLite decides type affinity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Affinity {
+    /// `INT`, `BIGINT` and the like, read as `int`.
+    Integer,
+    /// `REAL`, `FLOAT` and `DOUBLE`, read as `float`.
+    Real,
+    /// `DECIMAL` and `NUMERIC`, which PDO returns as strings so no precision is lost.
+    Decimal,
+    /// `BOOL` and MySQL's `TINYINT(1)`.
+    Boolean,
+    /// Strings, dates and times.
+    Text,
+    /// Binary strings.
+    Blob,
+    /// A type nothing is known about, which isn't checked.
+    Unknown
+}
+
+impl Affinity {
+    /// The affinity of a declared type like `VARCHAR(255)`.
+    pub fn of(sql_type: &str) -> Affinity {
+        let upper = sql_type.to_uppercase();
+        let has = |part: &str| upper.contains(part);
+        if upper.starts_with("TINYINT(1)") || has("BOOL") {
+            Affinity::Boolean
+        } else if has("INTERVAL") {
+            Affinity::Text
+        } else if has("POINT") || has("LINESTRING") || has("POLYGON") || has("GEOMETRY") {
+            // spatial types, including the `MULTI` ones and `GEOMETRYCOLLECTION`, are read in a driver-specific format,
+            // and `POINT` would otherwise be taken for an `INT`
+            Affinity::Unknown
+        } else if has("INT") || has("SERIAL") {
+            Affinity::Integer
+        } else if has("CHAR") || has("CLOB") || has("TEXT") || has("DATE") || has("TIME") || has("JSON") || has("UUID") || has("ENUM") {
+            Affinity::Text
+        } else if has("BLOB") || has("BINARY") || has("BYTEA") {
+            Affinity::Blob
+        } else if has("REAL") || has("FLOA") || has("DOUB") {
+            Affinity::Real
+        } else if has("DEC") || has("NUMERIC") || has("MONEY") {
+            Affinity::Decimal
+        } else {
+            Affinity::Unknown
+        }
+    }
+
+    /// The PHP types a value of this affinity can be read as. The first one is the one to suggest.
+    pub fn php_types(self) -> &'static [&'static str] {
+        match self {
+            Affinity::Integer => &["int"],
+            Affinity::Real => &["float"],
+            Affinity::Decimal => &["string", "float"],
+            Affinity::Boolean => &["bool", "int"],
+            Affinity::Text | Affinity::Blob => &["string"],
+            Affinity::Unknown => &[]
+        }
+    }
+
+    /// The PHP types a parameter of this affinity can be passed as, which the database converts.
+    pub fn param_types(self) -> &'static [&'static str] {
+        match self {
+            Affinity::Integer => &["int", "bool"],
+            Affinity::Real | Affinity::Decimal => &["float", "int", "string"],
+            other => other.php_types()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> (Schema, Vec<Diagnostic>) {
+        let mut schema = Schema::default();
+        let mut errors = Vec::new();
+        schema.parse_file("schema.sql", source, Dialect::Any, &mut errors);
+        (schema, errors)
+    }
+
+    // Name, type and nullability of every column of the only table in `source`.
+    fn columns(source: &str) -> Vec<(String, String, bool)> {
+        let (schema, errors) = parse(source);
+        assert_eq!(errors, Vec::new());
+        assert_eq!(schema.tables.len(), 1);
+        schema.tables[0].columns.iter().map(|column| (column.name.clone(), column.sql_type.clone(), column.nullable)).collect()
+    }
+
+    fn column(name: &str, sql_type: &str, nullable: bool) -> (String, String, bool) {
+        (name.to_string(), sql_type.to_string(), nullable)
+    }
+
+    #[test]
+    fn column_types_and_nullability() {
+        assert_eq!(
+            columns("CREATE TABLE Books (Id INT PRIMARY KEY, BookName VARCHAR(255) NOT NULL, Price DECIMAL(10, 2) DEFAULT 0, Blurb TEXT);"),
+            vec![column("Id", "INT", false), column("BookName", "VARCHAR(255)", false), column("Price", "DECIMAL(10,2)", true), column("Blurb", "TEXT", true)]
+        );
+    }
+
+    #[test]
+    fn composite_primary_key() {
+        assert_eq!(
+            columns("CREATE TABLE Loans (BookId INT, ReaderId INT, Note TEXT, PRIMARY KEY (BookId, ReaderId));"),
+            vec![column("BookId", "INT", false), column("ReaderId", "INT", false), column("Note", "TEXT", true)]
+        );
+        assert_eq!(
+            columns("CREATE TABLE Loans (BookId INT, ReaderId INT, CONSTRAINT pk PRIMARY KEY (BookId, ReaderId), FOREIGN KEY (ReaderId) REFERENCES Readers (Id));"),
+            vec![column("BookId", "INT", false), column("ReaderId", "INT", false)]
+        );
+    }
+
+    #[test]
+    fn serial_columns_are_not_null() {
+        assert_eq!(columns("CREATE TABLE t (id BIGSERIAL, n SERIAL UNIQUE);"), vec![column("id", "BIGSERIAL", false), column("n", "SERIAL", false)]);
+    }
+
+    #[test]
+    fn quoted_and_qualified_names() {
+        let (schema, errors) = parse("CREATE TABLE IF NOT EXISTS main.\"Book List\" (`Id` INT NOT NULL, [Name] TEXT);");
+        assert_eq!(errors, Vec::new());
+        let table = schema.table("other.book list").unwrap();
+        assert_eq!(table.name, "Book List");
+        assert!(table.column("id").is_some_and(|column| !column.nullable));
+        assert!(schema.has_column("NAME"));
+    }
+
+    #[test]
+    fn other_statements_are_skipped() {
+        let (schema, errors) = parse("-- the library\nPRAGMA foreign_keys = ON;\nCREATE INDEX i ON t (a);\nCREATE TABLE copy AS SELECT * FROM t;\nCREATE TABLE t (a INT)");
+        assert_eq!(errors, Vec::new());
+        let names: Vec<&str> = schema.tables.iter().map(|table| table.name.as_str()).collect();
+        assert_eq!(names, vec!["t"]);
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let (_, errors) = parse("CREATE TABLE t;\nCREATE TABLE u (a INT;\nCREATE TABLE IF v (a INT);");
+        let found: Vec<(usize, usize, &str)> = errors.iter().map(|error| (error.line, error.column, error.message.as_str())).collect();
+        assert_eq!(found, vec![
+            (1, 15, "expected `(` and the columns of `t`"),
+            (2, 16, "expected `)` after the columns"),
+            (3, 14, "expected `IF NOT EXISTS`")
+        ]);
+    }
+
+    #[test]
+    fn affinities() {
+        let types: Vec<Affinity> = ["INTEGER", "TINYINT(1)", "BOOLEAN", "VARCHAR(20)", "DATETIME", "DOUBLE PRECISION", "NUMERIC(5)", "BYTEA", "INTERVAL", "GEOMETRY"]
+            .iter().map(|sql_type| Affinity::of(sql_type)).collect();
+        assert_eq!(types, vec![
+            Affinity::Integer, Affinity::Boolean, Affinity::Boolean, Affinity::Text, Affinity::Text, Affinity::Real, Affinity::Decimal,
+            Affinity::Blob, Affinity::Text, Affinity::Unknown
+        ]);
+    }
+
+    #[test]
+    fn spatial_types_are_unknown() {
+        for sql_type in ["POINT", "MULTIPOINT", "LINESTRING", "MULTILINESTRING", "POLYGON", "MULTIPOLYGON", "GEOMETRY", "GEOMETRYCOLLECTION"] {
+            assert_eq!(Affinity::of(sql_type), Affinity::Unknown, "{}", sql_type);
+        }
+    }
+}
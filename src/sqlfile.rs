@@ -1,309 +1,1204 @@
-use std::collections::{HashMap, VecDeque};
+use std::{collections::{BTreeMap, VecDeque}, iter::Peekable};
 
+use crate::{diagnostic::Diagnostic, names, schema::Schema, Dialect};
+
+/// Text cut out of a query file. Remembers the byte offset every character came from so errors can point back into the file.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SourceText {
+    pub text: String,
+    pub offsets: Vec<usize>
+}
+
+impl SourceText {
+    /// Appends a character that was found at `offset` in the file.
+    pub fn push(&mut self, char: char, offset: usize) {
+        self.text.push(char);
+        self.offsets.push(offset);
+    }
+
+    /// Appends another piece of text from the same file.
+    pub fn push_text(&mut self, other: &SourceText) {
+        self.text.push_str(&other.text);
+        self.offsets.extend(&other.offsets);
+    }
+
+    /// Removes leading and trailing whitespace, keeping the offsets of the rest.
+    pub fn trim(&self) -> SourceText {
+        let skip = self.text.chars().count() - self.text.trim_start().chars().count();
+        let trimmed = self.text.trim();
+        let count = trimmed.chars().count();
+        SourceText { text: trimmed.to_string(), offsets: self.offsets[skip..skip + count].to_vec() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Byte offset in the file of the character at `index`, or just past the last character.
+    pub fn offset(&self, index: usize) -> usize {
+        match self.offsets.get(index) {
+            Some(offset) => *offset,
+            None => self.offsets.last().map_or(0, |offset| offset + 1)
+        }
+    }
+}
+
+/// A piece of a query file, as produced by `lex`.
 #[derive(Debug, PartialEq, Clone)]
 pub enum Thing {
-    Comment(String),
-    Sql(String),
-    EndOfQuery()
+    Comment(String, usize), // text, offset of the `--`
+    BlockComment(String, usize), // text between `/*` and `*/`, offset of the `/*`
+    Sql(SourceText),
+    EndOfQuery(usize) // offset of the `;`
 }
 
-#[derive(Debug)]
-pub struct SyntaxError {
+// Drops the query that is being lexed, back to its header or the end of the previous query.
+fn drop_query(things: &mut Vec<Thing>) {
+    while let Some(thing) = things.last() {
+        if matches!(thing, Thing::EndOfQuery(_)) {
+            break;
+        }
+        if let Some(Thing::Comment(text, _)) = things.pop() {
+            if text.starts_with('#') {
+                break;
+            }
+        }
+    }
+}
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum LexState {
+    Sql,
+    Comment(usize), // offset of the `--`
+    BlockComment(bool, usize), // kept in the SQL, offset of the `/*`
+    Quoted(char, usize) // quote character, offset of the opening quote
 }
 
-pub fn lex(text: String) -> Result<VecDeque<Thing>, SyntaxError> {
-    let mut double_quotes = false;
-    let mut single_quotes = false;
-    let mut backticks = false;
+// Optimizer hints (`/*+ ... */`) and MySQL's executable comments (`/*! ... */`) are read by the database,
+// so they stay in the SQL. Other block comments are dropped.
+fn is_kept_comment(text: &str) -> bool {
+    text.starts_with("/*+") || text.starts_with("/*!")
+}
 
-    let mut in_comment = false;
+/// Lexes a query file into comments and SQL in a single pass. The SQL keeps its line breaks and indentation.
+/// Problems are pushed to `errors` and the broken query is skipped, so the rest of the file is still lexed.
+pub fn lex(path: &str, text: &str, dialect: Dialect, errors: &mut Vec<Diagnostic>) -> VecDeque<Thing> {
+    let mut state = LexState::Sql;
+    let mut things: Vec<Thing> = Vec::new();
+    let mut current = SourceText::default();
+    // text of the block comment being lexed, when it is not kept in the SQL
+    let mut comment = String::new();
+    let mut last_char: Option<char> = None;
 
-    let mut is_next_dash = false;
+    let mut chars = text.char_indices().peekable();
 
-    let mut things: Vec<Thing> = Vec::new();
+    while let Some((offset, char)) = chars.next() {
+        let line_start = last_char.is_none_or(|last_char| last_char == '\n');
+        last_char = Some(char);
 
-    let mut current = String::new();
-    
-    for (i, char) in text.chars().enumerate() {
-        if in_comment {
-            if char == '\n' {
-                in_comment = false;
-                things.push(Thing::Comment(current));
-                current = String::new();
-            } else {
-                if !char.is_ascii_control() && !is_next_dash {
-                    current.push(char); 
+        match state {
+            LexState::Comment(start) => {
+                if char == '\n' {
+                    things.push(Thing::Comment(current.text, start));
+                    current = SourceText::default();
+                    // the line break still separates the SQL around the comment
+                    current.push(char, offset);
+                    state = LexState::Sql;
+                } else if !char.is_ascii_control() {
+                    current.push(char, offset);
                 }
-                is_next_dash = false;
             }
-        } else {
-            if !double_quotes && !single_quotes && !backticks {
-                match text.chars().nth(i + 1) {
-                    None => {}
-                    Some(next_char) => {
-                        if char == '-' && next_char == '-' {
-                            if current != "" {
-                                things.push(Thing::Sql(current));
-                            }
-                            current = String::new();
-                            in_comment = true;
-                            is_next_dash = true;
-                        } else {
-                            
-                        }
+            LexState::BlockComment(kept, start) => {
+                if char == '*' && chars.next_if(|(_, next_char)| *next_char == '/').is_some() {
+                    last_char = Some('/');
+                    if kept {
+                        current.push(char, offset);
+                        current.push('/', offset + 1);
+                    } else {
+                        things.push(Thing::BlockComment(comment, start));
+                        comment = String::new();
+                        // the comment still separates the SQL around it
+                        current.push(' ', start);
                     }
+                    state = LexState::Sql;
+                } else if kept {
+                    current.push(char, offset);
+                } else {
+                    comment.push(char);
                 }
-            }          
-        }
-        if !in_comment {
-            match text.chars().nth(i - 1) {
-                None => {}
-                Some(last_char) => {
-                    if last_char != '\\' {
-                        if char == '"' {
-                            double_quotes = !double_quotes;
-                        } else if char == '\'' {
-                            single_quotes = !single_quotes;
-                        } else if char == '`' {
-                            backticks = !backticks;
-                        }
+            }
+            LexState::Quoted(quote, start) => {
+                if line_start && text[offset..].starts_with("--#") {
+                    // a quote that is still open at the next header was never closed, so the header starts a new query
+                    errors.push(Diagnostic::new(path, text, start, "unterminated quote, expected a matching closing quote"));
+                    drop_query(&mut things);
+                    current = SourceText::default();
+                    chars.next();
+                    last_char = Some('-');
+                    state = LexState::Comment(offset);
+                    continue;
+                }
+                current.push(char, offset);
+                if char == '\\' && dialect.backslash_escapes(quote) {
+                    // the next character is escaped
+                    if let Some((offset, char)) = chars.next() {
+                        current.push(char, offset);
+                        last_char = Some(char);
+                    }
+                } else if char == quote {
+                    // a doubled quote is an escaped quote
+                    if let Some((offset, char)) = chars.next_if(|(_, next_char)| *next_char == quote) {
+                        current.push(char, offset);
+                        last_char = Some(char);
+                    } else {
+                        state = LexState::Sql;
                     }
                 }
             }
-            let mut push = true;
-            if !double_quotes && !single_quotes && !backticks {
-                if char == ';' {
-                    push = false;
+            LexState::Sql => {
+                if char == '-' && chars.next_if(|(_, next_char)| *next_char == '-').is_some() {
+                    if !current.is_empty() {
+                        things.push(Thing::Sql(current));
+                    }
+                    current = SourceText::default();
+                    last_char = Some('-');
+                    state = LexState::Comment(offset);
+                } else if char == '/' && chars.next_if(|(_, next_char)| *next_char == '*').is_some() {
+                    last_char = Some('*');
+                    let kept = is_kept_comment(&text[offset..]);
+                    if kept {
+                        current.push('/', offset);
+                        current.push('*', offset + 1);
+                    }
+                    state = LexState::BlockComment(kept, offset);
+                } else if char == ';' {
                     things.push(Thing::Sql(current));
-                    things.push(Thing::EndOfQuery());
-                    current = String::new();
-                } else if char.is_ascii_control() {
-                    push = false;
+                    things.push(Thing::EndOfQuery(offset));
+                    current = SourceText::default();
+                } else if char == '"' || char == '\'' || char == '`' {
+                    current.push(char, offset);
+                    state = LexState::Quoted(char, offset);
+                } else if char == '\r' && chars.peek().is_some_and(|(_, next_char)| *next_char == '\n') {
+                    // line breaks are always `\n`, so the output is the same on every platform
+                } else {
+                    current.push(char, offset);
                 }
             }
-            if push {
-                current.push(char);
+        }
+    }
+
+    match state {
+        LexState::Quoted(_, start) => {
+            errors.push(Diagnostic::new(path, text, start, "unterminated quote, expected a matching closing quote"));
+            drop_query(&mut things);
+        }
+        LexState::BlockComment(_, start) => {
+            errors.push(Diagnostic::new(path, text, start, "unterminated block comment, expected `*/`"));
+            drop_query(&mut things);
+        }
+        LexState::Comment(start) => things.push(Thing::Comment(current.text, start)),
+        LexState::Sql => {
+            if !current.text.trim().is_empty() {
+                things.push(Thing::Sql(current));
             }
         }
     }
 
-    let mut without_comments: Vec<&Thing> = Vec::new();
+    VecDeque::from(things)
 
-    for thing in &things {
-        match thing {
-            Thing::Comment(_) => {}
-            t => {
-                without_comments.push(t);
-            }
+}
+
+/// What a query method returns, chosen with a suffix on the header: `--#name:one`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cardinality {
+    /// `:one`, the first row or `null`
+    One,
+    /// `:many`, a generator of every row
+    Many,
+    /// `:exec`, nothing
+    Exec,
+    /// `:execrows`, the number of affected rows
+    ExecRows,
+    /// `:lastid`, the ID of the last inserted row
+    LastId
+}
+
+impl Cardinality {
+    fn from_suffix(suffix: &str) -> Option<Cardinality> {
+        match suffix {
+            "one" => Some(Cardinality::One),
+            "many" => Some(Cardinality::Many),
+            "exec" => Some(Cardinality::Exec),
+            "execrows" => Some(Cardinality::ExecRows),
+            "lastid" => Some(Cardinality::LastId),
+            _ => None
         }
     }
 
-    match without_comments.last() {
-        Some(last) => {
-            match **last {
-                Thing::EndOfQuery() => {}
-                _ => {
-                    return Err(SyntaxError {  });
-                }
-            }
+    /// The header suffix, without the `:`.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Cardinality::One => "one",
+            Cardinality::Many => "many",
+            Cardinality::Exec => "exec",
+            Cardinality::ExecRows => "execrows",
+            Cardinality::LastId => "lastid"
         }
-        _ => {}
     }
 
-    return Ok(VecDeque::from(things));
+    /// Whether the query returns rows, which need `@column: type` return values.
+    pub fn returns_rows(self) -> bool {
+        matches!(self, Cardinality::One | Cardinality::Many)
+    }
+}
+
+/// The SQL of one query, as cut out of its file by `lex_2`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct QueryText {
+    /// Path of the file the query is in
+    pub path: String,
+    /// Byte offset of the `--#name` header
+    pub header: usize,
+    /// Set when the header has a suffix like `:one`
+    pub cardinality: Option<Cardinality>,
+    /// Declared with `--#fragment name` instead of `--#name`
+    pub fragment: bool,
+    pub(crate) sql: SourceText,
+    /// Text and byte offset of every comment between the header and the `;`, for annotations
+    pub comments: Vec<(String, usize)>
+}
+
+impl QueryText {
+    /// The SQL between the header and the `;`, with the annotations still in it.
+    pub fn sql(&self) -> &str {
+        &self.sql.text
+    }
+}
+
+fn is_header(thing: &Thing) -> bool {
+    matches!(thing, Thing::Comment(text, _) if text.starts_with('#'))
+}
 
+// Skips the rest of a broken query, up to the next `;` or the next `--#name` header.
+fn skip_query(input: &mut VecDeque<Thing>) {
+    while let Some(thing) = input.front() {
+        if is_header(thing) {
+            break;
+        }
+        if let Some(Thing::EndOfQuery(_)) = input.pop_front() {
+            break;
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct FormatError;
+/// Splits lexed things into named queries, one query per loop. Queries are keyed by `false` and their name with the
+/// file name in front, fragments by `true` and their name, so the two can't clash. Problems are pushed to `errors`
+/// and lexing carries on with the next query.
+pub fn lex_2(path: &str, source: &str, filename: String, mut input: VecDeque<Thing>, mut base: BTreeMap<(bool, String), QueryText>, errors: &mut Vec<Diagnostic>) -> BTreeMap<(bool, String), QueryText> {
 
-pub fn lex_2(filename: String, mut input: VecDeque<Thing>, mut base: HashMap<String, String>) -> Result<HashMap<String, String>, FormatError> {
+    loop {
+        // whitespace between the end of the last query and the next header
+        while let Some(Thing::Sql(text)) = input.front() {
+            if !text.text.trim().is_empty() {
+                break;
+            }
+            input.pop_front();
+        }
+
+        let mut name: String;
+        let header: usize;
+        let mut cardinality = None;
+        let mut fragment = false;
 
-    let mut data: VecDeque<Thing> = VecDeque::new();
-    while (|| {
         match input.pop_front() {
-            Some(thing) => {
-                data.push_back(thing.clone());
-                thing != Thing::EndOfQuery()
+            Some(Thing::Comment(text, offset)) => {
+                if let Some(stripped) = text.strip_prefix('#') {
+                    if stripped.trim().is_empty() {
+                        errors.push(Diagnostic::new(path, source, offset, "expected a query name after `--#`"));
+                        skip_query(&mut input);
+                        continue;
+                    }
+                    match stripped.split_once(':') {
+                        Some((before, suffix)) => {
+                            cardinality = Cardinality::from_suffix(suffix.trim());
+                            if cardinality.is_none() {
+                                // the suffix starts after `--#name:`
+                                errors.push(Diagnostic::new(path, source, offset + 4 + before.len(), format!("unknown result kind `:{}`, expected `:one`, `:many`, `:exec`, `:execrows` or `:lastid`", suffix.trim())));
+                                skip_query(&mut input);
+                                continue;
+                            }
+                            name = before.trim().to_string();
+                        }
+                        None => name = stripped.trim().to_string()
+                    }
+                    if let Some(fragment_name) = name.strip_prefix("fragment ") {
+                        if cardinality.is_some() {
+                            errors.push(Diagnostic::new(path, source, offset, "fragments are included in other queries, so they can't have a suffix like `:one`"));
+                            skip_query(&mut input);
+                            continue;
+                        }
+                        name = fragment_name.trim().to_string();
+                        fragment = true;
+                    }
+                    header = offset;
+                } else {
+                    // a comment between queries
+                    continue;
+                }
+            }
+            Some(Thing::BlockComment(_, _)) => continue,
+            Some(Thing::Sql(text)) => {
+                let start = text.text.len() - text.text.trim_start().len();
+                errors.push(Diagnostic::new(path, source, text.offset(text.text[..start].chars().count()), "expected a `--#name` header before the query"));
+                skip_query(&mut input);
+                continue;
+            }
+            Some(Thing::EndOfQuery(offset)) => {
+                errors.push(Diagnostic::new(path, source, offset, "expected a `--#name` header before the query"));
+                continue;
             }
             None => {
-                false
+                return base
             }
         }
-    })() {
-        
-    }
 
-    let mut name: String = String::new();
+        let mut query = QueryText { path: path.to_string(), header, cardinality, fragment, sql: SourceText::default(), comments: Vec::new() };
+        let mut terminated = false;
 
-    match data.pop_front() {
-        Some(thing) => {
+        while let Some(thing) = input.pop_front() {
+            if is_header(&thing) {
+                // the next query starts before this one was closed
+                input.push_front(thing);
+                break;
+            }
             match thing {
-                Thing::Comment(text) => {
-                    if text.starts_with("#") {
-                        name = text.get(1..).unwrap().to_string()
-                    } else {
-                        return Err(FormatError)
-                    }
-                }
-                _ => {
-                    println!("155{:?}", data);
-                    return Err(FormatError)
+                Thing::Sql(text) => query.sql.push_text(&text),
+                Thing::EndOfQuery(_) => {
+                    terminated = true;
+                    break;
                 }
+                Thing::Comment(text, offset) | Thing::BlockComment(text, offset) => query.comments.push((text, offset))
             }
         }
-        None => {
-            
+
+        query.sql = query.sql.trim();
+
+        if query.sql.is_empty() {
+            errors.push(Diagnostic::new(path, source, header, format!("expected SQL after the `--#{}` header", name)));
+        } else if !terminated {
+            errors.push(Diagnostic::new(path, source, query.sql.offset(query.sql.offsets.len()), "expected `;` at the end of the query"));
+        } else {
+            // fragments are shared by every file, so they don't get the file name
+            let key = if fragment { (true, name.clone()) } else { (false, filename.clone() + "_" + &name) };
+            if let Some(first) = base.get(&key) {
+                let kind = if fragment { "fragment" } else { "query" };
+                errors.push(Diagnostic::new(path, source, header, format!("duplicate {} name `{}`", kind, name))
+                    .with_note(Diagnostic::note(path, source, first.header, "first defined here")));
+            } else {
+                let _ = base.insert(key, query);
+            }
         }
     }
 
-    if name == "" {
-        return Ok(base)
+}
+
+/// A piece of a query's SQL, as produced by `lex_sql`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlToken {
+    /// SQL that is passed to PDO as it is.
+    Sql(String),
+    /// `@expression: type`: the property name, the column or expression and the PHP type, which is empty until inferred.
+    Return(String, String, String),
+    /// `$name: type`: the name and the PHP type, which is empty until inferred.
+    Variable(String, String),
+    /// `[[ ... ]]`, left out when one of its variables is null.
+    Optional(Vec<SqlToken>),
+    /// `{{name}}` and the byte offset of the `{{`, replaced by the fragment's tokens before generation.
+    Include(String, usize)
+}
+
+/// The tokens of a query, with the tokens of optional fragments in place of the fragments.
+pub fn flatten(tokens: &[SqlToken]) -> Vec<&SqlToken> {
+    let mut flat = Vec::new();
+    for token in tokens {
+        match token {
+            SqlToken::Optional(inner) => flat.extend(flatten(inner)),
+            _ => flat.push(token)
+        }
     }
+    flat
+}
 
-    let _ = base.insert(filename.clone() + "_" + &name, "".to_string());
+/// A query that has been through all three lexers, ready for code generation.
+#[derive(Debug, Clone)]
+pub struct Query {
+    /// The name of the generated method, with the prefix of its file
+    pub name: String,
+    /// Where the query is and its SQL
+    pub text: QueryText,
+    /// The SQL with its parameters and return values, in order
+    pub tokens: Vec<SqlToken>,
+    /// What the generated method returns
+    pub cardinality: Cardinality,
+    /// Lines of the doc comment between the header and the SQL, see `doc_comment`
+    pub doc: Vec<String>
+}
 
-    for thing in data {
-        match thing {
-            Thing::Sql(text) => {
-                base.get_mut(&(filename.clone() + "_" + &name)).unwrap().push_str(&text);
+impl Query {
+    /// Works out what a lexed query returns and its doc comment. The tokens can't contain includes anymore.
+    pub(crate) fn new(source: &str, name: String, text: QueryText, tokens: Vec<SqlToken>) -> Result<Query, Diagnostic> {
+        check_variable_types(source, &text, &tokens)?;
+        let cardinality = cardinality(source, &text, &tokens)?;
+        let doc = doc_comment(source, &text, &tokens)?;
+        Ok(Query { name, text, tokens, cardinality, doc })
+    }
+}
+
+/// A `--#fragment`, or a query whose `{{name}}` includes haven't been resolved yet.
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    /// The name `{{name}}` includes it by
+    pub name: String,
+    /// Where it is declared and its SQL
+    pub text: QueryText,
+    /// The SQL with its parameters, and includes that aren't resolved yet
+    pub tokens: Vec<SqlToken>
+}
+
+/// Whether the tokens include a fragment anywhere.
+pub fn has_includes(tokens: &[SqlToken]) -> bool {
+    flatten(tokens).iter().any(|token| matches!(token, SqlToken::Include(_, _)))
+}
+
+// Splits the text of a comment into lines, without the `*` that block comments put in front of each line.
+fn comment_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split('\n').map(|line| {
+        let line = line.trim();
+        line.strip_prefix('*').unwrap_or(line).trim()
+    })
+}
+
+/// Collects the comments between a query's header and its SQL into doc comment lines. `@param $name` tags get
+/// the type of the parameter, so `--@param $id the book` becomes `@param int $id the book`.
+pub fn doc_comment(source: &str, query: &QueryText, tokens: &[SqlToken]) -> Result<Vec<String>, Diagnostic> {
+    let sql_start = query.sql.offset(0);
+    let mut doc = Vec::new();
+
+    for (text, offset) in query.comments.iter().filter(|(_, offset)| *offset < sql_start) {
+        for line in comment_lines(text) {
+            let error = |message: String| Diagnostic::new(&query.path, source, *offset, message);
+            if line.starts_with("@return") {
+                return Err(error("`@return` is generated from the query, use a suffix like `:one` on the header to change it".to_string()));
+            }
+            let Some(rest) = line.strip_prefix("@param ") else {
+                doc.push(line.to_string());
+                continue;
+            };
+            let rest = rest.trim_start();
+            let Some(name) = rest.split_whitespace().next().and_then(|word| word.strip_prefix('$')) else {
+                // the type is already there
+                doc.push(line.to_string());
+                continue;
+            };
+            let type_name = flatten(tokens).into_iter().find_map(|token| match token {
+                SqlToken::Variable(variable, type_name) if variable == name => Some(type_name),
+                _ => None
+            });
+            match type_name {
+                Some(type_name) => doc.push(format!("@param {} {}", type_name, rest)),
+                None => return Err(error(format!("`@param ${}` doesn't match a parameter of the query", name)))
+            }
+        }
+    }
+
+    // blank lines around the text, like the ones a `/**` block comment starts and ends with
+    while doc.last().is_some_and(|line| line.is_empty()) {
+        doc.pop();
+    }
+    let leading = doc.iter().take_while(|line| line.is_empty()).count();
+    doc.drain(..leading);
+    Ok(doc)
+}
+
+// The generated method takes every variable once, so all of its uses need the same type. `lex_sql` already points at
+// the use with the other type, this catches uses that come from different fragments.
+fn check_variable_types(source: &str, query: &QueryText, tokens: &[SqlToken]) -> Result<(), Diagnostic> {
+    let mut types: BTreeMap<&str, &str> = BTreeMap::new();
+    for token in flatten(tokens) {
+        let SqlToken::Variable(name, type_name) = token else {
+            continue;
+        };
+        match types.get(name.as_str()) {
+            Some(first) if first != type_name => {
+                return Err(Diagnostic::new(&query.path, source, query.header, format!("`${}` is used as both `{}` and `{}` in this query and the fragments it includes, so it needs the same type everywhere", name, first, type_name)));
             }
+            Some(_) => {}
+            None => {
+                types.insert(name, type_name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decides what a query returns. Queries without a suffix on their header return rows if they have `@` return values,
+/// the last insert ID if they start with `INSERT`, and the number of affected rows otherwise.
+pub fn cardinality(source: &str, query: &QueryText, tokens: &[SqlToken]) -> Result<Cardinality, Diagnostic> {
+    let has_returns = tokens.iter().any(|token| matches!(token, SqlToken::Return(..)));
+    let Some(cardinality) = query.cardinality else {
+        return Ok(match tokens.first() {
+            _ if has_returns => Cardinality::Many,
+            Some(SqlToken::Sql(sql)) if sql.to_lowercase().starts_with("insert") => Cardinality::LastId,
+            _ => Cardinality::ExecRows
+        });
+    };
+    if cardinality.returns_rows() && !has_returns {
+        return Err(Diagnostic::new(&query.path, source, query.header, format!("`:{}` queries need at least one `@column: type` return value", cardinality.suffix())));
+    }
+    if !cardinality.returns_rows() && has_returns {
+        return Err(Diagnostic::new(&query.path, source, query.header, format!("`:{}` queries don't return rows, so they can't have `@` return values", cardinality.suffix())));
+    }
+    Ok(cardinality)
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum SqlState {
+    Sql,
+    Name(usize), // index of the `$`
+    Type(bool, usize), // is a return value, index of the `$` or `@`
+    Quoted(char), // string literal or quoted identifier, closed by the same quote
+    Comment // a block comment kept by `lex`, like an optimizer hint
+}
+
+fn sigil(is_return: bool) -> &'static str {
+    if is_return { "@" } else { "$" }
+}
+
+fn is_type_char(char: char) -> bool {
+    char.is_alphanumeric() || char == '_' || char == '?'
+}
+
+/// An optional fragment is left out when one of its variables is null, so it needs at least one nullable variable
+/// and can't change which columns are returned. Fragments that include others are checked once they are expanded.
+pub fn check_fragment(fragment: &[SqlToken]) -> Result<(), String> {
+    let mut has_variables = false;
+    for token in fragment {
+        match token {
+            // only possible once includes are expanded, `lex_sql` catches the rest
+            SqlToken::Optional(_) => return Err("optional fragments can't be nested, and this one includes a fragment with `[[ ... ]]`".to_string()),
+            SqlToken::Return(_, expression, _) => return Err(format!("optional fragments can't contain return values like `@{}`", expression)),
+            // the type of a bare `$name` is inferred as nullable
+            SqlToken::Variable(_, type_name) if type_name.is_empty() => has_variables = true,
+            SqlToken::Variable(name, type_name) if type_name.ends_with("[]") => {
+                return Err(format!("optional fragments can't contain array parameters like `${}: {}`", name, type_name))
+            }
+            SqlToken::Variable(name, type_name) if !type_name.starts_with('?') => {
+                return Err(format!("`${}` decides whether the optional fragment is used, so its type must be nullable, like `?{}`", name, type_name))
+            }
+            SqlToken::Variable(_, _) => has_variables = true,
             _ => {}
         }
     }
+    if !has_variables {
+        return Err("optional fragments need a nullable variable that decides whether they are used".to_string());
+    }
+    Ok(())
+}
+
+// How the text after an `@` ends, see `return_expression`.
+enum ReturnEnd {
+    // `@expression: type`, with the length of the expression in characters
+    Type(usize),
+    // `@expression: type` with a `$parameter: type` in the expression, this many characters after the `@`
+    Parameter(usize),
+    // a MySQL user variable, which is left in the SQL
+    Sql
+}
 
-    
+// Skips the name and type of a `$parameter: type`, after its `$`. Returns the number of characters skipped.
+fn skip_parameter(rest: &mut Peekable<impl Iterator<Item = char>>) -> usize {
+    let mut length = 0;
+    while rest.next_if(|char| is_name_char(*char)).is_some() {
+        length += 1;
+    }
+    if rest.next_if_eq(&':').is_none() {
+        // a bare `$name`
+        return length;
+    }
+    length += 1;
+    while rest.next_if(|char| char.is_whitespace() || is_type_char(*char) || *char == '[' || *char == ']').is_some() {
+        length += 1;
+    }
+    length
+}
 
-    if !input.back().is_none() {
-        match lex_2(filename, input, base) {
-            Err(err) => {
-                return Err(err);
+// Finds the column or expression in `@expression: type`, which ends at the first `:` outside of brackets and
+// quotes. It can't reach past a `,`, `;` or `@`, so `@rank := @rank + 1` and `@rank` without a type are MySQL
+// user variables. `::` is a Postgres cast, which can be in the expression. Parameters can't be, but they are
+// skipped so `@COALESCE(a, $b: int) AS c: int` can be reported instead of being left in the SQL.
+fn return_expression(rest: impl Iterator<Item = char>, dialect: Dialect) -> ReturnEnd {
+    let mut rest = rest.peekable();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut length = 0;
+    let mut parameter = None;
+    while let Some(char) = rest.next() {
+        if let Some(quote_char) = quote {
+            if char == '\\' && dialect.backslash_escapes(quote_char) && rest.next().is_some() {
+                length += 1;
+            } else if char == quote_char {
+                quote = None;
             }
-            Ok(rec_output) => {
-                base = rec_output;
+        } else {
+            match char {
+                '\'' | '"' | '`' => quote = Some(char),
+                '(' => depth += 1,
+                ')' if depth == 0 => return ReturnEnd::Sql,
+                ')' => depth -= 1,
+                ':' if depth == 0 => match rest.peek() {
+                    Some('=') => return ReturnEnd::Sql,
+                    Some(':') => {
+                        rest.next();
+                        length += 1;
+                    }
+                    _ => return parameter.map_or(ReturnEnd::Type(length), ReturnEnd::Parameter)
+                },
+                ',' | ';' if depth == 0 => return ReturnEnd::Sql,
+                '$' if rest.next_if_eq(&'$').is_some() => length += 1,
+                '$' => {
+                    parameter = parameter.or(Some(length));
+                    length += skip_parameter(&mut rest);
+                }
+                '@' => return ReturnEnd::Sql,
+                _ => {}
             }
         }
+        length += 1;
     }
+    ReturnEnd::Sql
+}
 
-    Ok(base)
-    
+fn is_name_char(char: char) -> bool {
+    char.is_alphanumeric() || char == '_'
 }
 
-#[derive(Debug, Clone)]
+// The length in characters of a bare `@column` or `@table.column`, if the column is in the schema and it is followed
+// by a `,`, `)`, keyword or the end of the query like a selected column. `@rank := @rank + 1` and other names are
+// MySQL user variables, which are left in the SQL.
+fn bare_column(rest: impl Iterator<Item = char> + Clone, schema: &Schema) -> Option<usize> {
+    let name: String = rest.clone().take_while(|char| is_name_char(*char) || *char == '.').collect();
+    let column = name.rsplit('.').next()?;
+    if column.is_empty() || name.starts_with('.') || name.matches('.').count() > 1 || !schema.has_column(column) {
+        return None;
+    }
+    let mut after = rest.skip(name.chars().count()).skip_while(|char| char.is_whitespace());
+    match after.next() {
+        None | Some(',') | Some(')') => Some(name.chars().count()),
+        Some(char) if char.is_alphabetic() => Some(name.chars().count()),
+        Some(_) => None
+    }
+}
 
-pub enum SqlToken {
-    Sql(String),
-    Return(String, String), // name, php type name
-    Variable(String, String)
+// The property a return value gets in its row class: the alias of `COUNT(*) AS total`, the column of `b.Blurb`,
+// or the expression itself.
+fn property_name(expression: &str) -> String {
+    let unquote = |name: &str| name.trim_matches(|char| matches!(char, '"' | '`' | '[' | ']')).to_string();
+    let words: Vec<&str> = expression.split_whitespace().collect();
+    match words.as_slice() {
+        [.., keyword, alias] if keyword.eq_ignore_ascii_case("as") => unquote(alias),
+        [column] if !column.contains('(') => unquote(column.rsplit('.').next().unwrap_or(column)),
+        _ => expression.trim().to_string()
+    }
+}
+
+// The token for `$name: type` or `@expression: type`. Variables are checked here, return values by
+// `check_property` once it is known whether the query needs a row class.
+fn annotation_token(is_return: bool, current: String, type_name: String) -> Result<SqlToken, String> {
+    if !is_return {
+        if current.is_empty() {
+            return Err("expected a name between `$` and `:`".to_string());
+        }
+        names::check_variable(&current).map_err(|problem| format!("`${}` {}", current, problem))?;
+        return Ok(SqlToken::Variable(current, type_name));
+    }
+    let expression = current.trim().to_string();
+    Ok(SqlToken::Return(property_name(&expression), expression, type_name))
 }
 
-pub fn lex_sql(mut sql: String) -> Vec<SqlToken> {
+// Remembers the type of a `$variable: type` at `start`. Fails with the index of an earlier use of the variable with a
+// different type and a message. Bare variables are inferred later, with the type written elsewhere if there is one.
+fn declare(types: &mut BTreeMap<String, (String, usize)>, token: &SqlToken, start: usize) -> Result<(), (usize, String)> {
+    let SqlToken::Variable(name, type_name) = token else {
+        return Ok(());
+    };
+    if type_name.is_empty() {
+        return Ok(());
+    }
+    match types.get(name) {
+        Some((first, index)) if first != type_name => Err((*index, format!("`${}: {}` has a different type than the `${}: {}` before it, but the generated method takes `${}` once, so use the same type", name, type_name, name, first, name))),
+        Some(_) => Ok(()),
+        None => {
+            types.insert(name.clone(), (type_name.clone(), start));
+            Ok(())
+        }
+    }
+}
+
+// Remembers the property of a return value whose `@` is at `start`. Fails with the index of the `@` of an earlier return
+// value with the same property and a message, since the row class can't have two constructor parameters with one name.
+fn declare_property(properties: &mut BTreeMap<String, usize>, token: &SqlToken, start: usize) -> Result<(), (usize, String)> {
+    let SqlToken::Return(property, expression, _) = token else {
+        return Ok(());
+    };
+    match properties.get(property) {
+        Some(first) => Err((*first, format!("`@{}` becomes the property `{}`, like a return value before it, so give one of them another name with `AS alias`", expression, property))),
+        None => {
+            properties.insert(property.clone(), start);
+            Ok(())
+        }
+    }
+}
 
-    sql = sql + " ";
+fn check_property(token: &SqlToken) -> Result<(), String> {
+    let SqlToken::Return(property, expression, _) = token else {
+        return Ok(());
+    };
+    names::check_variable(property).map_err(|problem| if property == expression {
+        format!("`@{}` can't be used as a PHP property name, so give it one with `AS name`", expression)
+    } else {
+        format!("`@{}` becomes the property `{}`, which {}", expression, property, problem)
+    })
+}
 
-    let mut double_quotes = false;
-    let mut single_quotes = false;
-    let mut backticks = false;
+/// Finds the `$variable: type` parameters and `@column: type` return values in a query in a single pass.
+/// `source` is the text of the file the query is from. Nothing inside quotes or comments is a parameter,
+/// `$$` is a literal `$`, and `@@` and `@` without a type are left in the SQL for MySQL variables.
+/// `[[ ... ]]` marks an optional fragment, which becomes a `SqlToken::Optional`, and `{{name}}` includes a `--#fragment`.
+/// With a schema, bare `$variable`s and `@column`s are accepted with an empty type, which `parse_file` infers.
+pub fn lex_sql(source: &str, query: &QueryText, dialect: Dialect, schema: Option<&Schema>) -> Result<Vec<SqlToken>, Diagnostic> {
 
-    let mut in_variable = false;
-    let mut in_return = false;
-    let mut found_char = false;
+    let sql = &query.sql.text;
+    let error = |index: usize, message: String| Diagnostic::new(&query.path, source, query.sql.offset(index), message);
 
+    let mut state = SqlState::Sql;
     let mut out: Vec<SqlToken> = Vec::new();
 
     let mut current = String::new();
     let mut type_name = String::new();
+    // the tokens before the optional fragment that is being lexed, and the index of its `[[`
+    let mut outside: Option<(Vec<SqlToken>, usize)> = None;
+    // return values whose property names can't be used in PHP, with the index of their `@`
+    let mut property_errors = Vec::new();
+    // the type of every variable and the index of its first `$`
+    let mut types = BTreeMap::new();
+    // the property of every return value and the index of its `@`
+    let mut properties = BTreeMap::new();
+    let redeclared = |start: usize, (first, message): (usize, String)| {
+        error(start, message).with_note(Diagnostic::note(&query.path, source, query.sql.offset(first), "first used here"))
+    };
 
-    let mut past_colon = true;
+    let mut chars = sql.chars().enumerate().peekable();
 
-    for (i, char) in sql.chars().enumerate() {
-        if in_variable || in_return {
-            if !past_colon {
-                if char == ':' {
-                    past_colon = true;
-                    continue;
-                }
-                if char.is_whitespace() {
-                    panic!("Syntax error");
+    while let Some(&(i, char)) = chars.peek() {
+        match state {
+            SqlState::Sql => {
+                chars.next();
+                if char == '$' && chars.next_if(|(_, next_char)| *next_char == '$').is_some() {
+                    current.push('$');
+                } else if char == '@' && chars.next_if(|(_, next_char)| *next_char == '@').is_some() {
+                    current.push_str("@@");
+                } else if char == '@' {
+                    let length = match return_expression(chars.clone().map(|(_, char)| char), dialect) {
+                        ReturnEnd::Type(length) => length,
+                        ReturnEnd::Parameter(at) => {
+                            let name: String = chars.clone().skip(at + 1).map(|(_, char)| char).take_while(|char| is_name_char(*char)).collect();
+                            return Err(error(i + 1 + at, format!("return values can't contain parameters like `${}`, so select the expression in a subquery and return its alias, like `SELECT @total: int FROM (SELECT ... AS total ...) AS sub`", name)));
+                        }
+                        ReturnEnd::Sql => {
+                            match schema.and_then(|schema| bare_column(chars.clone().map(|(_, char)| char), schema)) {
+                                Some(length) => {
+                                    if !current.is_empty() {
+                                        out.push(SqlToken::Sql(current));
+                                    }
+                                    let expression: String = chars.by_ref().take(length).map(|(_, char)| char).collect();
+                                    let token = SqlToken::Return(property_name(&expression), expression, String::new());
+                                    declare_property(&mut properties, &token, i).map_err(|problem| redeclared(i, problem))?;
+                                    out.push(token);
+                                    current = String::new();
+                                }
+                                None => current.push('@')
+                            }
+                            continue;
+                        }
+                    };
+                    if !current.is_empty() {
+                        out.push(SqlToken::Sql(current));
+                    }
+                    current = chars.by_ref().take(length).map(|(_, char)| char).collect();
+                    if current.trim().is_empty() {
+                        return Err(error(i, "expected a column or expression between `@` and `:`".to_string()));
+                    }
+                    // the `:` before the type
+                    chars.next();
+                    state = SqlState::Type(true, i);
+                } else if char == '"' || char == '\'' || char == '`' {
+                    current.push(char);
+                    state = SqlState::Quoted(char);
+                } else if char == '/' && chars.next_if(|(_, next_char)| *next_char == '*').is_some() {
+                    current.push_str("/*");
+                    state = SqlState::Comment;
+                } else if char == '{' && chars.next_if(|(_, next_char)| *next_char == '{').is_some() {
+                    let mut fragment_name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) if chars.next_if(|(_, next_char)| *next_char == '}').is_some() => break,
+                            Some((_, char)) => fragment_name.push(char),
+                            None => return Err(error(i, "expected `}}` after the fragment name".to_string()))
+                        }
+                    }
+                    if fragment_name.trim().is_empty() {
+                        return Err(error(i, "expected a fragment name between `{{` and `}}`".to_string()));
+                    }
+                    if !current.is_empty() {
+                        out.push(SqlToken::Sql(current));
+                    }
+                    current = String::new();
+                    out.push(SqlToken::Include(fragment_name.trim().to_string(), query.sql.offset(i)));
+                } else if char == '[' && chars.next_if(|(_, next_char)| *next_char == '[').is_some() {
+                    if outside.is_some() {
+                        return Err(error(i, "optional fragments can't be nested".to_string()));
+                    }
+                    if !current.is_empty() {
+                        out.push(SqlToken::Sql(current));
+                    }
+                    current = String::new();
+                    outside = Some((std::mem::take(&mut out), i));
+                } else if char == ']' && chars.next_if(|(_, next_char)| *next_char == ']').is_some() {
+                    let Some((tokens, start)) = outside.take() else {
+                        return Err(error(i, "found `]]` without an optional fragment to close".to_string()));
+                    };
+                    if !current.is_empty() {
+                        out.push(SqlToken::Sql(current));
+                    }
+                    current = String::new();
+                    let fragment = std::mem::replace(&mut out, tokens);
+                    if !has_includes(&fragment) {
+                        check_fragment(&fragment).map_err(|message| error(start, message))?;
+                    }
+                    out.push(SqlToken::Optional(fragment));
+                } else if char == '$' {
+                    if !current.is_empty() {
+                        out.push(SqlToken::Sql(current));
+                    }
+                    current = String::new();
+                    state = SqlState::Name(i);
+                } else {
+                    current.push(char);
                 }
+            }
+            SqlState::Quoted(quote) => {
+                chars.next();
                 current.push(char);
-            } else {
-                if found_char {
-                    if !char.is_whitespace() && (char.is_alphanumeric() || char == '_' || char == '?') {
-                        type_name.push(char);
-                    } else {
-                        if in_return {
-                            out.push(SqlToken::Return(current.clone(), type_name.clone()));
-                        } else {
-                            out.push(SqlToken::Variable(current.clone(), type_name.clone()));
-                        }
-                        current = String::new();
+                if char == '\\' && dialect.backslash_escapes(quote) {
+                    if let Some((_, char)) = chars.next() {
                         current.push(char);
-                        type_name = String::new();
-                        in_return = false;
-                        in_variable = false;
-                        past_colon = true;
                     }
-                } else {
-                    if !char.is_whitespace() && (char.is_alphanumeric() || char == '_' || char == '?') {
-                        type_name.push(char);
-                        found_char = true;
+                } else if char == quote {
+                    if let Some((_, char)) = chars.next_if(|(_, next_char)| *next_char == quote) {
+                        current.push(char);
+                    } else {
+                        state = SqlState::Sql;
                     }
                 }
             }
-        } else {
-            if !single_quotes && !double_quotes && !backticks {
-                if char == '"' || char == '\'' || char == '`' {
-                    match sql.chars().nth(i - 1) {
-                        Some(prev_char) => {
-                            if prev_char != '\\' {
-                                if char == '\'' {
-                                    single_quotes = !single_quotes;
-                                }
-                                if char == '"' {
-                                    double_quotes = !double_quotes;
-                                }
-                                if char == '`' {
-                                    backticks = !backticks;
-                                }
-                            }
-                        }
-                        None => {
-                            
-                        }
+            SqlState::Comment => {
+                chars.next();
+                current.push(char);
+                if char == '*' && chars.next_if(|(_, next_char)| *next_char == '/').is_some() {
+                    current.push('/');
+                    state = SqlState::Sql;
+                }
+            }
+            SqlState::Name(start) => {
+                if schema.is_some() && char != ':' && !is_name_char(char) {
+                    // a bare `$name`, whose type is inferred from the schema
+                    out.push(annotation_token(false, current, String::new()).map_err(|message| error(start, message))?);
+                    current = String::new();
+                    state = SqlState::Sql;
+                    continue;
+                }
+                chars.next();
+                if char == ':' {
+                    state = SqlState::Type(false, start);
+                } else if char.is_whitespace() {
+                    return Err(error(i, format!("expected `:` and a type after `${}`", current)));
+                } else {
+                    current.push(char);
+                }
+            }
+            SqlState::Type(is_return, start) => {
+                if is_type_char(char) {
+                    chars.next();
+                    type_name.push(char);
+                } else if char == '[' && !type_name.is_empty() && chars.clone().nth(1).is_some_and(|(_, next_char)| next_char == ']') {
+                    // `$ids: int[]` takes an array, which is expanded into a list of placeholders
+                    if is_return {
+                        return Err(error(i, format!("`@{}` is a return value, so its type can't be an array", current)));
+                    }
+                    chars.next();
+                    chars.next();
+                    type_name.push_str("[]");
+                } else if type_name.is_empty() {
+                    if !char.is_whitespace() {
+                        return Err(error(i, format!("expected a PHP type after `{}{}:`", sigil(is_return), current)));
+                    }
+                    chars.next();
+                } else {
+                    // the character after the type is lexed again as SQL
+                    let token = annotation_token(is_return, current, type_name).map_err(|message| error(start, message))?;
+                    declare(&mut types, &token, start).map_err(|problem| redeclared(start, problem))?;
+                    declare_property(&mut properties, &token, start).map_err(|problem| redeclared(start, problem))?;
+                    if let Err(message) = check_property(&token) {
+                        property_errors.push((start, message));
                     }
+                    out.push(token);
+                    current = String::new();
+                    type_name = String::new();
+                    state = SqlState::Sql;
                 }
             }
-            if char == '$' {
-                in_variable = true;
-                out.push(SqlToken::Sql(current.clone()));
-                current = String::new();
-                past_colon = false;
-                type_name = String::new();
-                found_char = false;
-            } else if char == '@' {
-                in_return = true;
-                out.push(SqlToken::Sql(current.clone()));
-                current = String::new();
-                past_colon = false;
-                type_name = String::new();
-                found_char = false;
-            } else {
-                current.push(char);
+        }
+    }
+
+    if let Some((_, start)) = outside {
+        return Err(error(start, "expected `]]` at the end of the optional fragment".to_string()));
+    }
+
+    match state {
+        // `lex` already reported unterminated quotes and comments
+        SqlState::Sql | SqlState::Quoted(_) | SqlState::Comment => out.push(SqlToken::Sql(current)),
+        SqlState::Name(start) if schema.is_some() => {
+            out.push(annotation_token(false, current, String::new()).map_err(|message| error(start, message))?);
+        }
+        SqlState::Name(start) => {
+            return Err(error(start, format!("expected `:` and a type after `${}`", current)));
+        }
+        SqlState::Type(is_return, start) => {
+            if type_name.is_empty() {
+                return Err(error(start, format!("expected a PHP type after `{}{}:`", sigil(is_return), current)));
+            }
+            let token = annotation_token(is_return, current, type_name).map_err(|message| error(start, message))?;
+            declare(&mut types, &token, start).map_err(|problem| redeclared(start, problem))?;
+            declare_property(&mut properties, &token, start).map_err(|problem| redeclared(start, problem))?;
+            if let Err(message) = check_property(&token) {
+                property_errors.push((start, message));
             }
+            out.push(token);
+        }
+    }
+
+    // a `:one` query with a single return value returns it directly, without a row class that needs property names
+    let flat = flatten(&out);
+    let returns = flat.iter().filter(|token| matches!(token, SqlToken::Return(..))).count();
+    let includes = flat.iter().any(|token| matches!(token, SqlToken::Include(..)));
+    if query.cardinality != Some(Cardinality::One) || returns != 1 || includes {
+        if let Some((start, message)) = property_errors.into_iter().next() {
+            return Err(error(start, message));
         }
     }
 
-    if !past_colon {
-        let s: String = current.chars().take(10).collect();
-        panic!("Syntax error: expected colon near {}", s);
+    Ok(out)
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_file, resolve_includes, ParsedFile};
+
+    fn parse(source: &str, dialect: Dialect) -> ParsedFile {
+        let mut files = vec![parse_file("test.sql", "test", source, dialect, None)];
+        resolve_includes(&mut files, dialect, None);
+        files.pop().unwrap()
+    }
+
+    // The tokens of the only query in `source`, which has to lex without problems.
+    fn tokens(source: &str) -> Vec<SqlToken> {
+        let file = parse(source, Dialect::Any);
+        assert_eq!(file.diagnostics, Vec::new());
+        assert_eq!(file.queries.len(), 1);
+        file.queries[0].tokens.clone()
     }
 
-    if !in_return && !in_variable {
-        out.push(SqlToken::Sql(current));
+    // The only problem found in `source`.
+    fn error(source: &str) -> Diagnostic {
+        let mut file = parse(source, Dialect::Any);
+        assert_eq!(file.diagnostics.len(), 1, "{:?}", file.diagnostics);
+        file.diagnostics.pop().unwrap()
     }
 
-    out
+    fn sql(text: &str) -> SqlToken {
+        SqlToken::Sql(text.to_string())
+    }
+
+    fn column(name: &str, type_name: &str) -> SqlToken {
+        SqlToken::Return(name.to_string(), name.to_string(), type_name.to_string())
+    }
+
+    fn variable(name: &str, type_name: &str) -> SqlToken {
+        SqlToken::Variable(name.to_string(), type_name.to_string())
+    }
+
+    #[test]
+    fn empty_string_before_semicolon() {
+        let file = parse("--#a\nSELECT @Id: int FROM t WHERE b = '';\n--#b\nSELECT 1;\n", Dialect::Any);
+        assert_eq!(file.diagnostics, Vec::new());
+        assert_eq!(file.queries.len(), 2);
+        assert_eq!(file.queries[0].tokens, vec![sql("SELECT "), column("Id", "int"), sql(" FROM t WHERE b = ''")]);
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn spaces_around_header_names() {
+        let file = parse("--#a :one\nSELECT @Id: int FROM t;\n--# b \nSELECT 1;\n--#fragment  c \nAND 1 = 1;\n", Dialect::Any);
+        assert_eq!(file.diagnostics, Vec::new());
+        let names: Vec<&str> = file.queries.iter().map(|query| query.name.as_str()).collect();
+        assert_eq!(names, vec!["test_a", "test_b"]);
+        assert_eq!(file.queries[0].cardinality, Cardinality::One);
+        assert_eq!(file.fragments[0].name, "c");
+    }
+
+    #[test]
+    fn at_sign_in_string() {
+        assert_eq!(
+            tokens("--#a\nSELECT @Id: int FROM users WHERE email LIKE '%@example.com';"),
+            vec![sql("SELECT "), column("Id", "int"), sql(" FROM users WHERE email LIKE '%@example.com'")]
+        );
+    }
+
+    #[test]
+    fn mysql_variables_stay_in_the_sql() {
+        assert_eq!(tokens("--#a\nSELECT @@version;"), vec![sql("SELECT @@version")]);
+        assert_eq!(
+            tokens("--#a\nSELECT @Id: int, @rank := @rank + 1 FROM t;"),
+            vec![sql("SELECT "), column("Id", "int"), sql(", @rank := @rank + 1 FROM t")]
+        );
+    }
+
+    #[test]
+    fn doubled_dollar_is_a_literal_dollar() {
+        assert_eq!(tokens("--#a\nSELECT $$$$ SELECT 1 $$$$ WHERE a = $a: int;"), vec![sql("SELECT $$ SELECT 1 $$ WHERE a = "), variable("a", "int")]);
+    }
+
+    #[test]
+    fn expression_returns() {
+        assert_eq!(
+            tokens("--#a\nSELECT @COUNT(*) AS total: int, @b.Blurb: string, @x::int AS y: int FROM Books b;"),
+            vec![
+                sql("SELECT "),
+                SqlToken::Return("total".to_string(), "COUNT(*) AS total".to_string(), "int".to_string()),
+                sql(", "),
+                SqlToken::Return("Blurb".to_string(), "b.Blurb".to_string(), "string".to_string()),
+                sql(", "),
+                SqlToken::Return("y".to_string(), "x::int AS y".to_string(), "int".to_string()),
+                sql(" FROM Books b")
+            ]
+        );
+    }
+
+    #[test]
+    fn parameter_in_return_expression() {
+        let error = error("--#a\nSELECT @COALESCE(a, $def: int) AS v: int FROM t;");
+        assert!(error.message.starts_with("return values can't contain parameters like `$def`"), "{}", error.message);
+        assert_eq!((error.line, error.column), (2, 21));
+        // a MySQL variable compared to a parameter isn't a return value
+        assert_eq!(tokens("--#a\nSELECT 1 WHERE @var = $x: int;"), vec![sql("SELECT 1 WHERE @var = "), variable("x", "int")]);
+    }
+
+    #[test]
+    fn two_returns_with_one_property() {
+        let error = error("--#a\nSELECT @a.Id: int, @b.Id: int FROM a JOIN b ON a.Id = b.Id;");
+        assert_eq!(error.message, "`@b.Id` becomes the property `Id`, like a return value before it, so give one of them another name with `AS alias`");
+        assert_eq!((error.line, error.column), (2, 20));
+        assert_eq!((error.notes[0].line, error.notes[0].column), (2, 8));
+        // property names are case-sensitive in PHP
+        assert_eq!(tokens("--#a\nSELECT @a.Id: int, @b.id: int FROM a JOIN b ON a.Id = b.id;").len(), 5);
+    }
+
+    #[test]
+    fn variable_with_two_types() {
+        let error = error("--#a\nSELECT @Id: int FROM t WHERE Id IN ($ids: int[]) OR Id = $ids: int;");
+        assert_eq!((error.line, error.column), (2, 58));
+        assert_eq!((error.notes[0].line, error.notes[0].column), (2, 37));
+    }
+
+    #[test]
+    fn unterminated_quote_recovers_at_next_header() {
+        let file = parse("--#a\nSELECT 'oops FROM t;\n--#b\nSELECT @Id: int FROM t;\n", Dialect::Any);
+        assert_eq!(file.diagnostics.len(), 1);
+        assert_eq!(file.diagnostics[0].message, "unterminated quote, expected a matching closing quote");
+        assert_eq!((file.diagnostics[0].line, file.diagnostics[0].column), (2, 8));
+        let names: Vec<&str> = file.queries.iter().map(|query| query.name.as_str()).collect();
+        assert_eq!(names, vec!["test_b"]);
+    }
+
+    #[test]
+    fn backslashes_depend_on_the_dialect() {
+        let source = "--#a\nSELECT @Id: int FROM t WHERE path = 'C:\\';\n";
+        assert_eq!(parse(source, Dialect::Sqlite).diagnostics, Vec::new());
+        assert_eq!(parse(source, Dialect::MySql).diagnostics[0].message, "unterminated quote, expected a matching closing quote");
+    }
+
+    #[test]
+    fn doubled_quotes_are_escaped_quotes() {
+        assert_eq!(
+            tokens("--#a\nSELECT @Id: int FROM t WHERE name = 'it''s $x' AND note = \"a \"\"@b\"\"\";"),
+            vec![sql("SELECT "), column("Id", "int"), sql(" FROM t WHERE name = 'it''s $x' AND note = \"a \"\"@b\"\"\"")]
+        );
+    }
+
+    #[test]
+    fn optimizer_hints_are_kept() {
+        assert_eq!(tokens("--#a\nSELECT /*+ INDEX(t i) */ 1 /* gone */ FROM t;"), vec![sql("SELECT /*+ INDEX(t i) */ 1   FROM t")]);
+    }
+
+    #[test]
+    fn block_comment_around_a_whole_query() {
+        let file = parse("--#a\nSELECT 1;\n/*\n--#old\nSELECT 1;\n*/\n--#b\nSELECT 2;\n", Dialect::Any);
+        assert_eq!(file.diagnostics, Vec::new());
+        let names: Vec<&str> = file.queries.iter().map(|query| query.name.as_str()).collect();
+        assert_eq!(names, vec!["test_a", "test_b"]);
+    }
+
+    #[test]
+    fn unterminated_block_comment_runs_to_the_end() {
+        let file = parse("--#a\nSELECT 1 /* oops;\n--#b\nSELECT 2;\n", Dialect::Any);
+        assert_eq!(file.diagnostics.len(), 1);
+        assert_eq!(file.diagnostics[0].message, "unterminated block comment, expected `*/`");
+        assert_eq!((file.diagnostics[0].line, file.diagnostics[0].column), (2, 10));
+        assert_eq!(file.queries.len(), 0);
+    }
+
+    #[test]
+    fn fragment_names_dont_clash_with_query_names() {
+        let file = parse_file("a.sql", "a", "--#fragment a_x\nAND 1 = 1;\n--#x\nSELECT 1;\n", Dialect::Any, None);
+        assert_eq!(file.diagnostics, Vec::new());
+        assert_eq!(file.queries[0].name, "a_x");
+        assert_eq!(file.fragments[0].name, "a_x");
+    }
+
+    #[test]
+    fn included_optional_filter() {
+        let tokens = tokens("--#fragment filter\nAND y = $v: ?int;\n--#a\nSELECT 1 WHERE 1 = 1 [[ {{filter}} ]];");
+        assert_eq!(tokens[1], SqlToken::Optional(vec![sql(" "), sql("AND y = "), variable("v", "?int"), sql(" ")]));
+        let error = error("--#fragment filter\nAND y = $v: int;\n--#a\nSELECT 1 WHERE 1 = 1 [[ {{filter}} ]];");
+        assert_eq!(error.message, "`$v` decides whether the optional fragment is used, so its type must be nullable, like `?int`");
+    }
+
+    #[test]
+    fn lexing_never_panics() {
+        for text in ["", "-", "--", "--#", "'", "\\", "$", "@", "@@", "[[", "]]", "{{", "/*", "*/", ";", "\r\n", "\u{0}", "日本'", "--#a\n$x:"] {
+            let _ = parse(text, Dialect::Any);
+            let _ = parse(text, Dialect::MySql);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Checking queries against a SQLite database, with the `sqlite` feature.
+
+use rusqlite::{Connection, OpenFlags};
+
+use crate::{
+    columns::split_type,
+    diagnostic::Diagnostic,
+    schema::{Affinity, Schema},
+    sqlfile::{flatten, Query, SqlToken},
+    Error, ParsedFile
+};
+
+/// Creates an in-memory SQLite database with the tables of the schema files.
+pub fn in_memory(schema: &Schema) -> Result<Connection, Error> {
+    let connection = Connection::open_in_memory().map_err(|e| Error::Io(format!("Could not open an in-memory SQLite database: {}", e)))?;
+    for (path, source) in &schema.files {
+        connection.execute_batch(source).map_err(|e| Error::Config(format!("Could not load {} into SQLite: {}", path, e)))?;
+    }
+    Ok(connection)
+}
+
+/// Opens an existing SQLite database, which is never written to.
+pub fn open(path: &str) -> Result<Connection, Error> {
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(|e| Error::Io(format!("Could not open the SQLite database {}: {}", path, e)))
+}
+
+// The SQL PDO would run, with `?` for every variable, one `?` for arrays and every optional fragment used.
+fn prepared_sql(tokens: &[SqlToken]) -> String {
+    let mut sql = String::new();
+    for token in flatten(tokens) {
+        match token {
+            SqlToken::Sql(text) => sql.push_str(text),
+            SqlToken::Return(_, expression, _) => sql.push_str(expression),
+            SqlToken::Variable(_, _) => sql.push('?'),
+            SqlToken::Optional(_) | SqlToken::Include(_, _) => {}
+        }
+    }
+    sql
+}
+
+fn verify_query(connection: &Connection, file: &ParsedFile, query: &Query, check_types: bool) -> Vec<Diagnostic> {
+    let at = |message: String| Diagnostic::new(&file.path, &file.source, query.text.header, message);
+    let statement = match connection.prepare(&prepared_sql(&query.tokens)) {
+        Ok(statement) => statement,
+        Err(e) => return vec![at(format!("SQLite can't prepare query `{}`: {}", query.name, e))]
+    };
+
+    let returns: Vec<(&String, &String)> = flatten(&query.tokens).into_iter().filter_map(|token| match token {
+        SqlToken::Return(property, _, type_name) => Some((property, type_name)),
+        _ => None
+    }).collect();
+    // statements without return values can still return rows, like `INSERT ... RETURNING id`
+    if returns.is_empty() {
+        return Vec::new();
+    }
+    let columns = statement.columns();
+    if columns.len() != returns.len() {
+        return vec![at(format!("SQLite returns {} column(s) from query `{}`, but it has {} return value(s)", columns.len(), query.name, returns.len()))];
+    }
+
+    let mut diagnostics = Vec::new();
+    for (column, (property, type_name)) in columns.iter().zip(returns) {
+        let Some(decl_type) = column.decl_type().filter(|_| check_types) else {
+            continue;
+        };
+        let (_, base) = split_type(type_name);
+        let allowed = Affinity::of(decl_type).php_types();
+        let known = ["int", "float", "string", "bool"].contains(&base.as_str());
+        if known && !allowed.is_empty() && !allowed.contains(&base.as_str()) {
+            diagnostics.push(at(format!("SQLite says the return value `{}: {}` of query `{}` is `{}`, so it should be `{}`", property, type_name, query.name, decl_type, allowed[0])));
+        }
+    }
+    diagnostics
+}
+
+/// Prepares every query against the database, so syntax errors and unknown tables and columns are found before the
+/// PHP code runs. Also checks the number of columns SQLite returns against the return values, and with `check_types`
+/// their declared types, which [`crate::check_types`] already does if there is a schema. Nothing is executed.
+pub fn verify(connection: &Connection, files: &[ParsedFile], check_types: bool) -> Vec<Diagnostic> {
+    files.iter().flat_map(|file| file.queries.iter().flat_map(|query| verify_query(connection, file, query, check_types))).collect()
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::{parse_file, resolve_includes, Dialect};
+
+    const SCHEMA: &str = "CREATE TABLE Books (Id INTEGER PRIMARY KEY, Name TEXT NOT NULL, Price REAL);";
+
+    fn connection() -> Connection {
+        let mut schema = Schema::default();
+        let mut errors = Vec::new();
+        schema.parse_file("schema.sql", SCHEMA, Dialect::Sqlite, &mut errors);
+        assert_eq!(errors, Vec::new());
+        in_memory(&schema).unwrap()
+    }
+
+    fn parse(source: &str) -> Vec<ParsedFile> {
+        let mut files = vec![parse_file("test.sql", "test", source, Dialect::Sqlite, None)];
+        resolve_includes(&mut files, Dialect::Sqlite, None);
+        assert_eq!(files[0].diagnostics, Vec::new());
+        files
+    }
+
+    fn messages(source: &str) -> Vec<String> {
+        verify(&connection(), &parse(source), true).into_iter().map(|diagnostic| diagnostic.message).collect()
+    }
+
+    #[test]
+    fn unknown_table() {
+        assert_eq!(messages("--#a\nSELECT @Id: int FROM Authors;"), vec!["SQLite can't prepare query `test_a`: no such table: Authors"]);
+    }
+
+    #[test]
+    fn column_count() {
+        assert_eq!(
+            messages("--#a\nSELECT @Id: int, Name FROM Books;"),
+            vec!["SQLite returns 2 column(s) from query `test_a`, but it has 1 return value(s)"]
+        );
+        assert_eq!(messages("--#a\nSELECT @Id: int, @Name: string FROM Books;"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn declared_types() {
+        assert_eq!(
+            messages("--#a\nSELECT @Id: string, @Price: ?float FROM Books;"),
+            vec!["SQLite says the return value `Id: string` of query `test_a` is `INTEGER`, so it should be `int`"]
+        );
+    }
+
+    #[test]
+    fn arrays_and_optional_fragments() {
+        let source = "--#a\nSELECT @Id: int FROM Books WHERE Id IN ($ids: int[]) [[ AND Name = $name: ?string ]] AND Price < $max: float;";
+        let files = parse(source);
+        assert_eq!(prepared_sql(&files[0].queries[0].tokens), "SELECT Id FROM Books WHERE Id IN (?)  AND Name = ?  AND Price < ?");
+        assert_eq!(verify(&connection(), &files, true), Vec::new());
+    }
+}
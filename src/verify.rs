@@ -0,0 +1,169 @@
+use std::{collections::BTreeSet, fs};
+
+use safe_sql::{generate_sections, Config, ParsedFile, Section, SectionKind};
+
+const TRANSACTION_START: &str = "class Transaction extends TransactionBase {";
+
+// The identifier at the start of `text`, after any whitespace.
+fn identifier(text: &str) -> &str {
+    let text = text.trim_start();
+    let end = text.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(text.len());
+    &text[..end]
+}
+
+// Finds the query methods and classes declared in a previously generated file.
+// Strings and comments are skipped, so PHPDoc text like "the class of" isn't taken for a declaration.
+fn declared(old: &str) -> (BTreeSet<String>, BTreeSet<String>) {
+    let mut methods = BTreeSet::new();
+    let mut classes = BTreeSet::new();
+    // the query methods are at depth 1 of class Transaction, the classes after it at depth 0
+    let (text, mut depth, mut in_transaction) = match old.find(TRANSACTION_START) {
+        Some(i) => (&old[i + TRANSACTION_START.len()..], 1, true),
+        None => (old, 0, false)
+    };
+    let mut i = 0;
+
+    while let Some(c) = text[i..].chars().next() {
+        let rest = &text[i..];
+        i += c.len_utf8();
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth <= 0 {
+                    in_transaction = false;
+                }
+            }
+            '\'' | '"' => {
+                let mut escaped = false;
+                match rest[1..].find(|next| {
+                    let end = next == c && !escaped;
+                    escaped = next == '\\' && !escaped;
+                    end
+                }) {
+                    Some(end) => i += end + 1,
+                    None => break
+                }
+            }
+            '/' if rest.starts_with("/*") => match rest[2..].find("*/") {
+                Some(end) => i += end + 3,
+                None => break
+            },
+            _ if c == '#' || rest.starts_with("//") => match rest.find('\n') {
+                Some(end) => i += end,
+                None => break
+            },
+            c if c.is_alphabetic() || c == '_' => {
+                let word = identifier(rest);
+                i += word.len() - c.len_utf8();
+                let name = identifier(&text[i..]);
+                match word {
+                    "function" if in_transaction && depth == 1 && !name.is_empty() => {
+                        methods.insert(name.to_string());
+                    }
+                    "class" if depth == 0 && !name.is_empty() => {
+                        classes.insert(name.to_string());
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    (methods, classes)
+}
+
+// Renders the output in memory and compares it to the existing output file.
+// Returns a summary of every method and class that differs when the file is out of date.
+pub fn verify(config: &Config, files: &[ParsedFile]) -> Result<(), Vec<String>> {
+    let sections = generate_sections(config, files).map_err(|e| vec![e.to_string()])?;
+    let new: String = sections.iter().map(|section| section.code.as_str()).collect();
+    let old = fs::read_to_string(&config.out).map_err(|e| vec![format!("Could not read {}: {}", config.out, e)])?;
+
+    if old == new {
+        return Ok(());
+    }
+
+    let mut changes = vec![format!("{} is out of date, run safe_sql to regenerate it:", config.out)];
+    changes.extend(differences(&old, &sections));
+    Err(changes)
+}
+
+// Lists the methods and classes of `sections` that were added or changed since `old` was generated,
+// and the ones `old` declares that are gone.
+fn differences(old: &str, sections: &[Section]) -> Vec<String> {
+    let mut changes = Vec::new();
+    let (old_methods, old_classes) = declared(old);
+    let mut new_methods = BTreeSet::new();
+    let mut new_classes = BTreeSet::new();
+
+    for section in sections {
+        let existed = match section.kind {
+            SectionKind::Method => {
+                new_methods.insert(section.name.clone());
+                old_methods.contains(&section.name)
+            }
+            SectionKind::Class => {
+                new_classes.insert(section.name.clone());
+                old_classes.contains(&section.name)
+            }
+            _ => true
+        };
+        if !old.contains(&section.code) {
+            changes.push(format!("  {} {} {}", if existed { "changed" } else { "added" }, section.kind, section.name));
+        }
+    }
+
+    for name in old_methods.difference(&new_methods) {
+        changes.push(format!("  removed method {}", name));
+    }
+    for name in old_classes.difference(&new_classes) {
+        changes.push(format!("  removed class {}", name));
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(kind: SectionKind, name: &str, code: &str) -> Section {
+        Section { kind, name: name.to_string(), code: code.to_string() }
+    }
+
+    const OLD: &str = "<?php\nclass TransactionBase {\n    function run() {}\n}\nclass Transaction extends TransactionBase {\n\
+        /** Returns the class of every function in the shop, and \"class Quoted {\" */\n\
+        public function getBooks(): array { $sql = \"SELECT class FROM t -- function x\"; }\n\
+        // function commented() {}\n\
+        public function oldQuery(): void {}\n\
+        }\n\
+        /** The function of a class of books */\n\
+        class getBooks { public function __construct() {} }\n\
+        class oldQuery { public function run() {} }\n";
+
+    #[test]
+    fn declarations_outside_strings_and_comments() {
+        let (methods, classes) = declared(OLD);
+        assert_eq!(methods.into_iter().collect::<Vec<_>>(), vec!["getBooks", "oldQuery"]);
+        assert_eq!(classes.into_iter().collect::<Vec<_>>(), vec!["getBooks", "oldQuery"]);
+    }
+
+    #[test]
+    fn summary() {
+        let sections = vec![
+            section(SectionKind::Header, "Transaction", "<?php\n"),
+            section(SectionKind::Method, "getBooks", "public function getBooks(): array { $sql = \"SELECT 1\"; }"),
+            section(SectionKind::Method, "newQuery", "public function newQuery(): void {}"),
+            section(SectionKind::Header, "Transaction", "}"),
+            section(SectionKind::Class, "getBooks", "class getBooks { public function __construct() {} }")
+        ];
+        assert_eq!(differences(OLD, &sections), vec![
+            "  changed method getBooks",
+            "  added method newQuery",
+            "  removed method oldQuery",
+            "  removed class oldQuery"
+        ]);
+    }
+}
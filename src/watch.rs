@@ -0,0 +1,65 @@
+use std::{fs, path::{Path, PathBuf}, thread::sleep, time::{Duration, SystemTime}};
+
+use safe_sql::{check, files::{query_files, schema_files}, generate, read_queries, Config};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// path, modification time and length of every file that affects the output
+type Snapshot = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+fn stamp(path: &Path) -> (PathBuf, Option<SystemTime>, u64) {
+    match fs::metadata(path) {
+        Ok(meta) => (path.to_path_buf(), meta.modified().ok(), meta.len()),
+        Err(_) => (path.to_path_buf(), None, 0)
+    }
+}
+
+fn snapshot(config_path: &Path) -> Snapshot {
+    let mut files = vec![stamp(config_path)];
+    if let Ok(config) = Config::load(config_path) {
+        if let Ok(query_files) = query_files(&config) {
+            for file in query_files {
+                files.push(stamp(&file.path));
+            }
+        }
+        if let Ok(schema_files) = schema_files(&config) {
+            for path in schema_files {
+                files.push(stamp(&path));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+// Regenerates the output once. Errors are returned instead of exiting so the watcher keeps running.
+fn rebuild(config_path: &Path) -> Result<bool, String> {
+    let config = Config::load(config_path).map_err(|e| e.to_string())?;
+    let files = read_queries(&config).map_err(|e| e.to_string())?;
+    let out = generate(&config, &files).map_err(|e| e.to_string())?;
+    for warning in check(&files) {
+        println!("{}\n", warning);
+    }
+    if fs::read_to_string(&config.out).is_ok_and(|old| old == out) {
+        return Ok(false);
+    }
+    fs::write(&config.out, out).map_err(|e| format!("Could not write {}: {}", config.out, e))?;
+    Ok(true)
+}
+
+pub fn watch(config_path: &Path) -> ! {
+    println!("Watching {} for changes", config_path.display());
+    let mut last: Option<Snapshot> = None;
+    loop {
+        let current = snapshot(config_path);
+        if last.as_ref() != Some(&current) {
+            match rebuild(config_path) {
+                Ok(true) => println!("Regenerated output"),
+                Ok(false) => println!("Output is up to date"),
+                Err(str) => println!("{}", str)
+            }
+            last = Some(current);
+        }
+        sleep(POLL_INTERVAL);
+    }
+}